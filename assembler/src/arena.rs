@@ -44,6 +44,27 @@ impl<'a> FileArena<'a> {
         let contents = std::fs::read_to_string(&path)?;
         Ok(self.add_contents(path, contents))
     }
+
+    /// Adds a file with `contents` that is not on the disk
+    #[cfg(test)]
+    pub fn add_source<'b: 'a>(&'b self, path: &'a Path, contents: &str) -> FileRef<'a> {
+        self.add_contents(path, contents.to_owned())
+    }
+
+    /// Same as [`FileArena::add`], but returns the already loaded file if `path` was added before
+    pub fn get_or_add<'b: 'a, C: Into<Cow<'a, Path>>>(
+        &'b self,
+        path: C,
+    ) -> std::io::Result<FileRef<'a>> {
+        let path = path.into();
+        let existing = self
+            .files
+            .borrow()
+            .iter()
+            .find(|x| x.file == path)
+            .map(|x| FileRef::new(&x.file, x.contents.as_str()));
+        existing.map_or_else(|| self.add(path), Ok)
+    }
 }
 
 impl<'a> Default for FileArena<'a> {
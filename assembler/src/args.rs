@@ -1,6 +1,13 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use pest::Parser as _;
+
+use crate::{
+    codegen::symbols::RS_COUNTER,
+    error::{WarningFlag, WarningOptions},
+    parser::{parse_constant, ASMParser, Rule},
+};
 
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -10,8 +17,79 @@ pub struct Args {
     out: PathBuf,
     #[clap(short, long)]
     listing: Option<Option<PathBuf>>,
+    /// Directory where included files are searched, after the directory of the including file
+    #[clap(short = 'I', long = "include-dir")]
+    include_dirs: Vec<PathBuf>,
+    /// Defines a symbol, the value is 1 if it is not given
+    #[clap(short = 'D', value_name = "NAME[=VALUE]", value_parser = parse_define)]
+    defines: Vec<(String, u32)>,
+    /// Writes the symbol table to a file
+    #[clap(long)]
+    symbols: Option<PathBuf>,
+    /// Format of the symbol table
+    #[clap(long, value_enum, default_value_t = SymbolsFormat::Text)]
+    symbols_format: SymbolsFormat,
+    /// Only writes the symbols exported with `XDEF` or `GLOBAL`
+    #[clap(long)]
+    exported_only: bool,
+    /// Symbols that only differ in case are the same symbol
+    #[clap(long)]
+    case_insensitive: bool,
+    /// Only the first characters of symbols are significant
+    #[clap(long, value_name = "LENGTH", value_parser = clap::value_parser!(u16).range(1..))]
+    significant_length: Option<u16>,
+    /// Enables a warning, disables it with `no-`, `all` enables every warning and `error` makes them errors
+    #[clap(short = 'W', value_name = "[no-]WARNING", value_parser = WarningFlag::from_str)]
+    warnings: Vec<WarningFlag>,
+    /// Branches whose target is too far away for a word displacement become `JMP` or `JSR` to its address
+    #[clap(long)]
+    promote_branches: bool,
+    /// Format of the errors and warnings
+    #[clap(long, value_enum, default_value_t = DiagnosticsFormat::Text)]
+    diagnostics_format: DiagnosticsFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SymbolsFormat {
+    Text,
+    Json,
+    /// C header with the constants and the addresses of the labels
+    CHeader,
+    /// Symbol assignments for a GNU ld linker script
+    LdScript,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DiagnosticsFormat {
+    /// Messages with the lines of the source they refer to
+    Text,
+    /// JSON document with every diagnostic and its location
+    Json,
+    /// SARIF 2.1.0 log
+    Sarif,
+}
+
+/// Parses `NAME[=VALUE]`, the value is written like an expression of the source without symbols
+fn parse_define(s: &str) -> Result<(String, u32), String> {
+    let (name, value) = s.split_once('=').unwrap_or((s, "1"));
+    let valid_name = ASMParser::parse(Rule::symbol, name)
+        .ok()
+        .and_then(|mut pairs| pairs.next())
+        .map(|pair| pair.as_str())
+        == Some(name);
+    if !valid_name {
+        return Err(format!("`{name}` is not a valid symbol name"));
+    }
+    if name.eq_ignore_ascii_case(RS_COUNTER) {
+        return Err(format!("`{name}` is defined by the assembler"));
+    }
+    let value = parse_constant(value).ok_or_else(|| format!("`{value}` is not a valid value"))?;
+    Ok((name.to_owned(), value as u32))
 }
 
+/// Paths searched for includes after the ones given with `-I`
+const INCLUDE_ENV_VAR: &str = "P68KASM_INCLUDE";
+
 impl Args {
     pub fn config(self) -> Config {
         Config {
@@ -24,6 +102,30 @@ impl Args {
                 })
             }),
             out: self.out,
+            include_dirs: self
+                .include_dirs
+                .into_iter()
+                .chain(
+                    std::env::var_os(INCLUDE_ENV_VAR)
+                        .map(|paths| std::env::split_paths(&paths).collect::<Vec<_>>())
+                        .unwrap_or_default(),
+                )
+                .collect(),
+            defines: self.defines,
+            symbols: self.symbols,
+            symbols_format: self.symbols_format,
+            exported_only: self.exported_only,
+            case_insensitive: self.case_insensitive,
+            significant_length: self.significant_length.map(usize::from),
+            warnings: self.warnings.into_iter().fold(
+                WarningOptions::default(),
+                |mut options, flag| {
+                    options.apply(flag);
+                    options
+                },
+            ),
+            promote_branches: self.promote_branches,
+            diagnostics_format: self.diagnostics_format,
         }
     }
 }
@@ -33,4 +135,43 @@ pub struct Config {
     pub input_file: PathBuf,
     pub out: PathBuf,
     pub listing: Option<PathBuf>,
+    pub include_dirs: Vec<PathBuf>,
+    pub defines: Vec<(String, u32)>,
+    pub symbols: Option<PathBuf>,
+    pub symbols_format: SymbolsFormat,
+    pub exported_only: bool,
+    pub case_insensitive: bool,
+    pub significant_length: Option<usize>,
+    pub warnings: WarningOptions,
+    pub promote_branches: bool,
+    pub diagnostics_format: DiagnosticsFormat,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_define() {
+        let value = |s| parse_define(s).map(|(_, value)| value);
+        assert_eq!(parse_define("DEBUG"), Ok(("DEBUG".to_owned(), 1)));
+        assert_eq!(value("X=0x10"), Ok(0x10));
+        assert_eq!(value("X=$FF_FF"), Ok(0xFFFF));
+        assert_eq!(value("X=%101"), Ok(5));
+        assert_eq!(value("X=0b101"), Ok(5));
+        assert_eq!(value("X=@17"), Ok(15));
+        assert_eq!(value("X=-1"), Ok(0xFFFFFFFF));
+        assert_eq!(value("X=4000000000"), Ok(4_000_000_000));
+        assert!(value("X=4294967296").is_err());
+        assert_eq!(value("X=1Fh"), Ok(0x1F));
+        assert_eq!(value("X=101b"), Ok(5));
+        assert_eq!(value("X=17q"), Ok(15));
+        assert_eq!(value("X='AB'"), Ok(0x4142));
+        assert_eq!(value("X=(1<<4)!2"), Ok(18));
+        assert!(value("X=0x").is_err());
+        assert!(value("X=*").is_err());
+        assert!(value("X=Y").is_err());
+        assert!(parse_define("1X=1").is_err());
+        assert!(parse_define("__RS=1").is_err());
+    }
 }
@@ -1,29 +1,43 @@
 use crate::{
-    error::{map_op_bin, SymbolResult},
+    error::{
+        map_code_bin, map_op_bin, CodeError, SymbolError, SymbolErrorKind, SymbolResult, Warning,
+        WarningKind,
+    },
     file::FileRef,
     parser::{parse_expression, Rule},
 };
-use pest::iterators::{Pair, Pairs};
+use pest::{
+    iterators::{Pair, Pairs},
+    Span,
+};
+use std::ops::RangeInclusive;
 
-use self::symbols::SymbolMap;
+use self::symbols::{Symbol, SymbolKind, SymbolMap};
 
+pub mod section;
 pub mod srec;
 pub mod symbols;
 
-type CodeResult<'code, T = ()> = SymbolResult<'code, T>;
+type CodeResult<'code, T = ()> = Result<T, CodeError<'code>>;
 
 pub struct Statement<'code> {
     pub label: Option<Pair<'code, Rule>>,
+    /// Value of the label if it is not the address of the statement
+    pub label_value: Option<Symbol>,
     pub start_addr: Option<u32>,
     pub code: Vec<u8>,
+    /// Uninitialised bytes reserved after the code
+    pub reserved: u32,
 }
 
 impl<'a> From<(Option<Pair<'a, Rule>>, Option<u32>, Vec<u8>)> for Statement<'a> {
     fn from((label, start_addr, code): (Option<Pair<'a, Rule>>, Option<u32>, Vec<u8>)) -> Self {
         Self {
             label,
+            label_value: None,
             start_addr,
             code,
+            reserved: 0,
         }
     }
 }
@@ -34,7 +48,10 @@ pub fn code_for_statement<'a, M: SymbolMap>(
     symbols: &M,
     current_file: FileRef<'a>,
     dry_run: bool,
+    promote_branches: bool,
+    warnings: &mut Vec<Warning<'a>>,
 ) -> CodeResult<'a, Statement<'a>> {
+    let span = p.as_span();
     Ok(match p.as_rule() {
         Rule::instruction => {
             let mut inner = p.into_inner();
@@ -52,8 +69,18 @@ pub fn code_for_statement<'a, M: SymbolMap>(
                 .unwrap();
             Statement {
                 label,
-                start_addr: IntSize::W.aligned(pc),
-                code: code_for_instr(instr, pc, symbols, current_file, dry_run)?,
+                label_value: None,
+                start_addr: IntSize::W.aligned(pc, span, current_file)?,
+                code: code_for_instr(
+                    instr,
+                    pc,
+                    symbols,
+                    current_file,
+                    dry_run,
+                    promote_branches,
+                    warnings,
+                )?,
+                reserved: 0,
             }
         }
         Rule::org => {
@@ -70,11 +97,201 @@ pub fn code_for_statement<'a, M: SymbolMap>(
                     }
                 })
                 .unwrap();
-            let expr = parse_expression(expr.into_inner(), symbols.get_failing(), current_file)?;
+            let expr = layout_expression(expr, symbols, current_file)?;
             (label, Some(expr as u32), vec![]).into()
         }
-        Rule::equ => todo!(),
+        Rule::equ | Rule::set => {
+            let kind = if p.as_rule() == Rule::set {
+                SymbolKind::Set
+            } else {
+                SymbolKind::Equ
+            };
+            let mut inner = p.into_inner();
+            let label = inner.next();
+            let value = layout_expression(inner.next().unwrap(), symbols, current_file)?;
+            Statement {
+                label,
+                label_value: Some(Symbol {
+                    value: value as u32,
+                    section: None,
+                    kind,
+                    size: 0,
+                }),
+                start_addr: None,
+                code: vec![],
+                reserved: 0,
+            }
+        }
+        Rule::reg => {
+            let mut inner = p.into_inner();
+            let label = inner.next();
+            let mask = reglist_mask(inner.next().unwrap());
+            Statement {
+                label,
+                label_value: Some(Symbol {
+                    value: mask as u32,
+                    section: None,
+                    kind: SymbolKind::RegisterList,
+                    size: 0,
+                }),
+                start_addr: None,
+                code: vec![],
+                reserved: 0,
+            }
+        }
         Rule::define_constant => {
+            let mut inner = p.into_inner();
+            let mut label = inner.next();
+            let mnemonic = label
+                .take()
+                .and_then(|first| {
+                    if first.as_rule() == Rule::sol_label {
+                        label = Some(first);
+                        inner.next()
+                    } else {
+                        Some(first)
+                    }
+                })
+                .unwrap();
+            let size = size_of_mnemonic(mnemonic, current_file, warnings)?;
+            let mut res = Vec::new();
+            for x in inner {
+                data_for_item(size, x, symbols, current_file, &mut res, warnings)?;
+            }
+            (label, size.aligned(pc, span, current_file)?, res).into()
+        }
+        Rule::define_storage => {
+            let mut inner = p.into_inner();
+            let mut label = inner.next();
+            let mnemonic = label
+                .take()
+                .and_then(|first| {
+                    if first.as_rule() == Rule::sol_label {
+                        label = Some(first);
+                        inner.next()
+                    } else {
+                        Some(first)
+                    }
+                })
+                .unwrap();
+            let size = size_of_mnemonic(mnemonic, current_file, warnings)?;
+            let count = inner.next().unwrap();
+            let reserved = size.reserved(
+                layout_expression(count.clone(), symbols, current_file)?,
+                &count,
+                current_file,
+            )?;
+            Statement {
+                label,
+                label_value: None,
+                start_addr: size.aligned(pc, span, current_file)?,
+                code: vec![],
+                reserved,
+            }
+        }
+        _ => return Err(unexpected(&p, current_file)),
+    })
+}
+
+/// Value of an expression that the layout depends on, which is evaluated without waiting for the
+/// symbols defined after it
+fn layout_expression<'b, M: SymbolMap>(
+    p: Pair<'b, Rule>,
+    symbols: &M,
+    current_file: FileRef<'b>,
+) -> SymbolResult<'b, i32> {
+    parse_expression(p.into_inner(), symbols.get_failing(), current_file)
+        .map_err(|errs| errs.into_iter().map(SymbolError::in_layout).collect())
+}
+
+/// Size of a `DC` or `DS`, `.W` if it is not given
+fn size_of_mnemonic<'a>(
+    mnemonic: Pair<'a, Rule>,
+    current_file: FileRef<'a>,
+    warnings: &mut Vec<Warning<'a>>,
+) -> CodeResult<'a, IntSize> {
+    let span = mnemonic.as_span();
+    match mnemonic.into_inner().next() {
+        Some(size) => int_size_to_enum(&size, current_file),
+        None => {
+            warnings.push(Warning::new(
+                WarningKind::ImplicitSize,
+                span,
+                current_file,
+                format!("`{}` without a size is `.W`", span.as_str()),
+            ));
+            Ok(IntSize::default())
+        }
+    }
+}
+
+/// Address reached by an alignment directive (`EVEN`, `ALIGN` or `CNOP`) at `pc`
+pub fn aligned_for_statement<'a, M: SymbolMap>(
+    p: Pair<'a, Rule>,
+    pc: u32,
+    symbols: &M,
+    current_file: FileRef<'a>,
+    warnings: &mut Vec<Warning<'a>>,
+) -> Result<u32, CodeError<'a>> {
+    let span = p.as_span();
+    let (offset, align) = match p.as_rule() {
+        Rule::even => (0, 2),
+        Rule::align => (
+            0,
+            layout_expression(p.into_inner().next().unwrap(), symbols, current_file)?,
+        ),
+        Rule::cnop => {
+            let mut inner = p.into_inner();
+            map_op_bin(
+                layout_expression(inner.next().unwrap(), symbols, current_file),
+                layout_expression(inner.next().unwrap(), symbols, current_file),
+                |offset, align| (offset, align),
+            )?
+        }
+        _ => return Err(unexpected(&p, current_file)),
+    };
+    if align <= 0 {
+        return Err(CodeError::Alignment {
+            value: align,
+            span,
+            file: current_file,
+        });
+    }
+    if align.count_ones() != 1 {
+        warnings.push(Warning::new(
+            WarningKind::OddAlignment,
+            span,
+            current_file,
+            format!("alignment {align} is not a power of two"),
+        ));
+    }
+    let align = align as i64;
+    u32::try_from(pc as i64 + (offset as i64 - pc as i64).rem_euclid(align)).map_err(|_| {
+        CodeError::AddressSpace {
+            span,
+            file: current_file,
+        }
+    })
+}
+
+/// Label, value and new RS counter of an `RSRESET`, `RSSET` or `RS` with the counter at `rs`.
+///
+/// `RS` aligns the counter to its size before taking its value.
+pub fn rs_for_statement<'a, M: SymbolMap>(
+    p: Pair<'a, Rule>,
+    rs: u32,
+    symbols: &M,
+    current_file: FileRef<'a>,
+) -> CodeResult<'a, (Option<Pair<'a, Rule>>, u32, u32)> {
+    Ok(match p.as_rule() {
+        Rule::rsreset => (None, 0, 0),
+        Rule::rsset => {
+            let value =
+                layout_expression(p.into_inner().next().unwrap(), symbols, current_file)? as u32;
+            (None, value, value)
+        }
+        Rule::rs => {
+            let span = p.as_span();
             let mut inner = p.into_inner();
             let mut label = inner.next();
             let size = label
@@ -90,52 +307,83 @@ pub fn code_for_statement<'a, M: SymbolMap>(
                 .unwrap()
                 .into_inner()
                 .next()
-                .map(|p| int_size_to_enum(&p))
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
                 .unwrap_or_default();
-            let mut res = Vec::new();
-            for x in inner {
-                data_for_item(size, x, symbols, current_file, &mut res)?;
-            }
-            (label, size.aligned(pc), res).into()
+            let count = inner.next().unwrap();
+            let reserved = size.reserved(
+                layout_expression(count.clone(), symbols, current_file)?,
+                &count,
+                current_file,
+            )?;
+            let value = size.aligned(rs, span, current_file)?.unwrap_or(rs);
+            let rs = value.checked_add(reserved).ok_or(CodeError::AddressSpace {
+                span,
+                file: current_file,
+            })?;
+            (label, value, rs)
         }
-        Rule::define_storage => todo!(),
-        _ => unreachable!(),
+        _ => return Err(unexpected(&p, current_file)),
     })
 }
 
+/// Contents of a quoted string, with the escape sequences replaced
+pub fn string_value(p: &Pair<Rule>) -> String {
+    let quoted = p.as_str();
+    let mut res = String::with_capacity(quoted.len());
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        res.push(match c {
+            '\\' => match chars.next().unwrap() {
+                'n' => '\n',
+                'r' => '\r',
+                '0' => '\0',
+                c => c,
+            },
+            c => c,
+        });
+    }
+    res
+}
+
 fn data_for_item<'b, M: SymbolMap>(
     size: IntSize,
     pair: Pair<'b, Rule>,
     symbols: &M,
     current_file: FileRef<'b>,
     data: &mut Vec<u8>,
+    warnings: &mut Vec<Warning<'b>>,
 ) -> CodeResult<'b> {
     match pair.as_rule() {
-        Rule::string => todo!(),
+        Rule::dc_string => {
+            // Padded with zeros to a multiple of the size
+            let start = data.len();
+            data.extend_from_slice(string_value(&pair.into_inner().next().unwrap()).as_bytes());
+            let len = data.len() - start;
+            data.resize(start + len.next_multiple_of(size as usize), 0);
+        }
         Rule::expression => {
             let span = pair.as_span();
-            let start_pos = span.start_pos().line_col();
             let value = parse_expression(pair.into_inner(), symbols, current_file)? as u32;
+            let mut truncated = |size_name| {
+                warnings.push(Warning::new(
+                    WarningKind::Truncation,
+                    span,
+                    current_file,
+                    format!("value ${value:X} does not fit in a {size_name}, it is truncated"),
+                ));
+            };
             match size {
+                // Negative values are fine as long as they fit signed
                 IntSize::B => {
-                    if value > 0xff {
-                        eprintln!(
-                            "expression @ {}:{}:{} is bigger than expected (byte), truncating",
-                            current_file.path.display(),
-                            start_pos.0,
-                            start_pos.1
-                        )
+                    if !size_range(size).contains(&(value as i32 as i64)) {
+                        truncated("byte");
                     }
                     data.push((value & 0xff) as u8);
                 }
                 IntSize::W => {
-                    if value > 0xffff {
-                        eprintln!(
-                            "expression @ {}:{}:{} is bigger than expected (word), truncating",
-                            current_file.path.display(),
-                            start_pos.0,
-                            start_pos.1
-                        )
+                    if !size_range(size).contains(&(value as i32 as i64)) {
+                        truncated("word");
                     }
                     data.push(((value & 0xff00) >> 8) as u8);
                     data.push((value & 0xff) as u8);
@@ -143,7 +391,7 @@ fn data_for_item<'b, M: SymbolMap>(
                 IntSize::L => data.extend_from_slice(&value.to_be_bytes()),
             }
         }
-        _ => unreachable!(),
+        _ => return Err(unexpected(&pair, current_file)),
     };
     Ok(())
 }
@@ -156,6 +404,7 @@ pub enum IntSize {
     L = 4,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for IntSize {
     fn default() -> Self {
         Self::W
@@ -163,31 +412,221 @@ impl Default for IntSize {
 }
 
 impl IntSize {
-    pub const fn aligned(&self, pc: u32) -> Option<u32> {
+    /// Next multiple of the size after `pc`, `None` if `pc` already is one. An error on `span` if it
+    /// is past the last address.
+    pub fn aligned<'a>(
+        &self,
+        pc: u32,
+        span: Span<'a>,
+        current_file: FileRef<'a>,
+    ) -> CodeResult<'a, Option<u32>> {
         let m = pc % (*self as u32);
         if m == 0 {
-            None
+            Ok(None)
         } else {
-            Some(pc - m + (*self as u32))
+            (pc - m)
+                .checked_add(*self as u32)
+                .map(Some)
+                .ok_or(CodeError::AddressSpace {
+                    span,
+                    file: current_file,
+                })
         }
     }
+
+    /// Bytes taken by `count` items of the size, as counted by the pair `p`
+    fn reserved<'a>(
+        &self,
+        count: i32,
+        p: &Pair<'a, Rule>,
+        current_file: FileRef<'a>,
+    ) -> CodeResult<'a, u32> {
+        let count = u32::try_from(count).map_err(|_| CodeError::NegativeCount {
+            count,
+            span: p.as_span(),
+            file: current_file,
+        })?;
+        count
+            .checked_mul(*self as u32)
+            .ok_or(CodeError::ReservedSize {
+                count,
+                size: *self as u32,
+                span: p.as_span(),
+                file: current_file,
+            })
+    }
+}
+
+/// Error for a pair that the grammar does not allow where it is found
+fn unexpected<'b>(p: &Pair<'b, Rule>, current_file: FileRef<'b>) -> CodeError<'b> {
+    CodeError::Internal {
+        message: format!("unexpected {:?} `{}`", p.as_rule(), p.as_str()),
+        span: p.as_span(),
+        file: current_file,
+    }
 }
 
-fn int_size_to_enum(p: &Pair<Rule>) -> IntSize {
+/// Error for an instruction that the grammar accepts but that can not be assembled yet
+fn unsupported<'b>(p: &Pair<'b, Rule>, current_file: FileRef<'b>) -> CodeError<'b> {
+    CodeError::Unsupported {
+        mnemonic: p.as_str().split_whitespace().next().unwrap_or_default(),
+        span: p.as_span(),
+        file: current_file,
+    }
+}
+
+fn int_size_to_enum<'b>(p: &Pair<'b, Rule>, current_file: FileRef<'b>) -> CodeResult<'b, IntSize> {
     match p.as_span().as_str().to_uppercase().as_str() {
-        ".B" => IntSize::B,
-        ".W" => IntSize::W,
-        ".L" => IntSize::L,
-        x => unreachable!("{x:?}"),
+        ".B" => Ok(IntSize::B),
+        ".W" => Ok(IntSize::W),
+        ".L" => Ok(IntSize::L),
+        _ => Err(unexpected(p, current_file)),
+    }
+}
+
+/// Signed 16 bit displacement or address
+const SIGNED_WORD: RangeInclusive<i64> = -0x8000..=0x7FFF;
+
+/// Values of an immediate or a `DC` of `size`, which can be signed or unsigned
+const fn size_range(size: IntSize) -> RangeInclusive<i64> {
+    match size {
+        IntSize::B => -0x80..=0xFF,
+        IntSize::W => -0x8000..=0xFFFF,
+        IntSize::L => i32::MIN as i64..=u32::MAX as i64,
+    }
+}
+
+const fn size_field(size: IntSize) -> &'static str {
+    match size {
+        IntSize::B => "a byte",
+        IntSize::W => "a word",
+        IntSize::L => "a long word",
+    }
+}
+
+/// `value` if it is in `range`, which is not checked on a dry run because the symbols may not have their
+/// final values
+fn in_range<'b>(
+    value: i32,
+    range: RangeInclusive<i64>,
+    field: &'static str,
+    span: Span<'b>,
+    current_file: FileRef<'b>,
+    dry_run: bool,
+) -> SymbolResult<'b, i32> {
+    if dry_run || range.contains(&(value as i64)) {
+        Ok(value)
+    } else {
+        Err(vec![SymbolError::with_kind(
+            span,
+            current_file,
+            SymbolErrorKind::OutOfRange {
+                value: value as i64,
+                field,
+                min: *range.start(),
+                max: *range.end(),
+            },
+        )])
+    }
+}
+
+/// Warns about a word or long access to an odd absolute address
+fn odd_address<'b>(
+    size: IntSize,
+    address: i32,
+    span: Span<'b>,
+    current_file: FileRef<'b>,
+    warnings: &mut Vec<Warning<'b>>,
+) {
+    if size != IntSize::B && address & 1 != 0 {
+        let size = if size == IntSize::W { "word" } else { "long" };
+        warnings.push(
+            Warning::new(
+                WarningKind::OddAddress,
+                span,
+                current_file,
+                format!("{size} access to odd address ${:X}", address as u32),
+            )
+            .with_note(
+                "The 68000 raises an address error on word and long accesses to odd addresses",
+            ),
+        );
     }
 }
 
+/// Extension words of the immediate data `p` of an operation of `size`. A value that does not fit
+/// is truncated with a warning, like the values of a `DC`.
+fn immediate_extension<'b, M: SymbolMap>(
+    p: Pair<'b, Rule>,
+    size: IntSize,
+    symbols: &M,
+    current_file: FileRef<'b>,
+    warnings: &mut Vec<Warning<'b>>,
+) -> CodeResult<'b, Vec<u8>> {
+    let span = p.as_span();
+    let value = parse_expression(p.into_inner(), symbols, current_file)?;
+    if !size_range(size).contains(&(value as i64)) {
+        warnings.push(Warning::new(
+            WarningKind::Truncation,
+            span,
+            current_file,
+            format!(
+                "value ${:X} does not fit in {}, it is truncated",
+                value as u32,
+                size_field(size)
+            ),
+        ));
+    }
+    Ok(match size {
+        IntSize::B => vec![0x00, (value & 0xFF) as u8],
+        IntSize::W => ((value & 0xFFFF) as u16).to_be_bytes().to_vec(),
+        IntSize::L => value.to_be_bytes().to_vec(),
+    })
+}
+
 fn get_mode_reg_extra_for_ea<'b, M: SymbolMap>(
     p: Pair<'b, Rule>,
     size: IntSize,
     symbols: &M,
     current_file: FileRef<'b>,
+    dry_run: bool,
+    warnings: &mut Vec<Warning<'b>>,
+) -> CodeResult<'b, (u8, u8, Vec<u8>)> {
+    mode_reg_extra(p, size, true, symbols, current_file, dry_run, warnings)
+}
+
+/// Mode, register and extension words of the address of `PEA`, `LEA`, `JMP` or `JSR`, which is
+/// only calculated, so it can be odd
+fn get_mode_reg_extra_for_control_ea<'b, M: SymbolMap>(
+    p: Pair<'b, Rule>,
+    symbols: &M,
+    current_file: FileRef<'b>,
+    dry_run: bool,
+    warnings: &mut Vec<Warning<'b>>,
+) -> CodeResult<'b, (u8, u8, Vec<u8>)> {
+    mode_reg_extra(
+        p,
+        IntSize::L,
+        false,
+        symbols,
+        current_file,
+        dry_run,
+        warnings,
+    )
+}
+
+/// Mode, register and extension words of an effective address, which is accessed with `size` if
+/// `access` is set
+fn mode_reg_extra<'b, M: SymbolMap>(
+    p: Pair<'b, Rule>,
+    size: IntSize,
+    access: bool,
+    symbols: &M,
+    current_file: FileRef<'b>,
+    dry_run: bool,
+    warnings: &mut Vec<Warning<'b>>,
 ) -> CodeResult<'b, (u8, u8, Vec<u8>)> {
+    let span = p.as_span();
     Ok(match p.as_rule() {
         Rule::Dn => (
             0b000,
@@ -218,35 +657,98 @@ fn get_mode_reg_extra_for_ea<'b, M: SymbolMap>(
             // dbg!(&p);
             let mut inner = p.into_inner();
             let d16 = inner.next().unwrap();
-            let disp = parse_expression(d16.into_inner(), symbols, current_file)? as u16;
+            let d16_span = d16.as_span();
+            let disp = parse_expression(d16.into_inner(), symbols, current_file)?;
+            let disp = in_range(
+                disp,
+                SIGNED_WORD,
+                "a signed word displacement",
+                d16_span,
+                current_file,
+                dry_run,
+            )? as u16;
             let reg_no = inner.next().unwrap().as_str().parse::<u8>().unwrap();
             (0b101, reg_no & 0b111, disp.to_be_bytes().to_vec())
         }
         Rule::absolute_short => {
             let value = parse_expression(p.into_inner(), symbols, current_file)?;
+            let value = in_range(
+                value,
+                SIGNED_WORD,
+                "a short address",
+                span,
+                current_file,
+                dry_run,
+            )?;
+            if access && !dry_run {
+                odd_address(size, value, span, current_file, warnings);
+            }
             (
                 0b111,
-                0b001,
+                0b000,
                 ((value & 0xFFFF) as u16).to_be_bytes().to_vec(),
             )
         }
         Rule::absolute_long => {
             let value = parse_expression(p.into_inner(), symbols, current_file)?;
+            if access && !dry_run {
+                odd_address(size, value, span, current_file, warnings);
+            }
             (0b111, 0b001, value.to_be_bytes().to_vec())
         }
-        Rule::immediate_data => {
-            let value = parse_expression(p.into_inner(), symbols, current_file)?;
-            (
-                0b111,
-                0b100,
-                match size {
-                    IntSize::B => vec![0x00, (value & 0xFF) as u8],
-                    IntSize::W => ((value & 0xFFFF) as u16).to_be_bytes().to_vec(),
-                    IntSize::L => value.to_be_bytes().to_vec(),
-                },
-            )
+        Rule::immediate_data => (
+            0b111,
+            0b100,
+            immediate_extension(p, size, symbols, current_file, warnings)?,
+        ),
+        _ => return Err(unexpected(&p, current_file)),
+    })
+}
+
+/// Register mask of a `reglist`, bit 0 is D0 and bit 15 is A7
+fn reglist_mask(p: Pair<Rule>) -> u16 {
+    let mut mask = 0;
+    for range in p.into_inner() {
+        let mut regs = range.into_inner().map(|reg| {
+            let offset = if reg.as_rule() == Rule::An { 8 } else { 0 };
+            offset
+                + reg
+                    .into_inner()
+                    .next()
+                    .unwrap()
+                    .as_str()
+                    .parse::<u16>()
+                    .unwrap()
+        });
+        let first = regs.next().unwrap();
+        let last = regs.next().unwrap_or(first);
+        for bit in first.min(last)..=first.max(last) {
+            mask |= 1 << bit;
         }
-        _ => unreachable!(),
+    }
+    mask
+}
+
+/// Register mask of a `reglist` or a `reglist_symbol`
+fn reglist_or_symbol_mask<'b, M: SymbolMap>(
+    p: Pair<'b, Rule>,
+    symbols: &M,
+    current_file: FileRef<'b>,
+) -> SymbolResult<'b, u16> {
+    if p.as_rule() == Rule::reglist {
+        return Ok(reglist_mask(p));
+    }
+    let name = p.as_str().trim_end();
+    symbols.get_register_list(name).ok_or_else(|| {
+        vec![SymbolError::with_kind(
+            p.as_span(),
+            current_file,
+            if symbols.get(name).is_some() {
+                SymbolErrorKind::NotRegisterList
+            } else {
+                SymbolErrorKind::Undefined
+            },
+        )]
     })
 }
 
@@ -256,24 +758,121 @@ enum SmallSize {
     W,
 }
 
-fn small_size_to_enum(p: &Pair<Rule>) -> SmallSize {
+fn small_size_to_enum<'b>(
+    p: &Pair<'b, Rule>,
+    current_file: FileRef<'b>,
+) -> CodeResult<'b, SmallSize> {
     match p.as_span().as_str().to_uppercase().as_str() {
-        ".B" => SmallSize::B,
-        ".W" => SmallSize::W,
-        _ => unreachable!(),
+        ".B" => Ok(SmallSize::B),
+        ".W" => Ok(SmallSize::W),
+        _ => Err(unexpected(p, current_file)),
     }
 }
 
+/// Address a branch jumps to. On a dry run it is an estimate, and `None` if it is not known yet or if
+/// the size of the branch does not depend on it, which is when it is not `promotable`
+fn branch_target<'b, M: SymbolMap>(
+    p: Pair<'b, Rule>,
+    symbols: &M,
+    current_file: FileRef<'b>,
+    dry_run: bool,
+    promotable: bool,
+) -> SymbolResult<'b, Option<u32>> {
+    let name = p.as_str().trim_end();
+    if dry_run && !promotable {
+        return Ok(None);
+    }
+    if dry_run {
+        // Labels after the branch are not defined yet, so the previous pass tells how far they are
+        return Ok(symbols
+            .get_failing()
+            .get(name)
+            .or_else(|| symbols.get_previous(name)));
+    }
+    match symbols.get(name) {
+        Some(address) => Ok(Some(address)),
+        None => Err(vec![SymbolError::with_kind(
+            p.as_span(),
+            current_file,
+            SymbolErrorKind::Undefined,
+        )]),
+    }
+}
+
+/// Code of a `Bcc`, or of a `BRA` or `BSR` with `cond` 0b0000 and 0b0001.
+///
+/// A branch that does not reach its target is an error, unless `promote` is set and it has a word
+/// displacement, which is replaced by a `JMP` or `JSR` to the absolute address, skipped with the
+/// opposite condition for a `Bcc`.
+fn code_for_branch<'b>(
+    cond: u16,
+    size: SmallSize,
+    target: Option<u32>,
+    pc: u32,
+    span: Span<'b>,
+    current_file: FileRef<'b>,
+    promote: bool,
+) -> SymbolResult<'b, Vec<u8>> {
+    let opcode = 0b0110_0000_0000_0000u16 | cond << 8;
+    // Unknown targets on a dry run take the size of the branch that reaches them
+    let distance = target.map_or(2, |target| target as i64 - (pc as i64 + 2));
+    // A byte displacement of 0 means that the displacement is a word
+    let fits = match size {
+        SmallSize::B => distance != 0 && (i8::MIN as i64..=i8::MAX as i64).contains(&distance),
+        SmallSize::W => SIGNED_WORD.contains(&distance),
+    };
+    Ok(match size {
+        _ if fits => match size {
+            SmallSize::B => (opcode | (distance as u8 as u16)).to_be_bytes().to_vec(),
+            SmallSize::W => {
+                let mut res = opcode.to_be_bytes().to_vec();
+                res.extend_from_slice(&(distance as i16).to_be_bytes());
+                res
+            }
+        },
+        SmallSize::W if promote => {
+            let target = target.unwrap();
+            let mut res = match cond {
+                // JMP (xxx).L
+                0b0000 => vec![0x4E, 0xF9],
+                // JSR (xxx).L
+                0b0001 => vec![0x4E, 0xB9],
+                // B!cc.B over the JMP (xxx).L
+                _ => {
+                    let skip = 0b0110_0000_0000_0000u16 | (cond ^ 1) << 8 | 6;
+                    let mut res = skip.to_be_bytes().to_vec();
+                    res.extend_from_slice(&[0x4E, 0xF9]);
+                    res
+                }
+            };
+            res.extend_from_slice(&target.to_be_bytes());
+            res
+        }
+        _ => {
+            return Err(vec![SymbolError::with_kind(
+                span,
+                current_file,
+                SymbolErrorKind::BranchOutOfRange {
+                    distance,
+                    short: size == SmallSize::B,
+                },
+            )])
+        }
+    })
+}
+
 fn code_for_instr<'b, M: SymbolMap>(
     p: Pair<'b, Rule>,
     pc: u32,
     symbols: &M,
     current_file: FileRef<'b>,
     dry_run: bool,
+    promote_branches: bool,
+    warnings: &mut Vec<Warning<'b>>,
 ) -> CodeResult<'b, Vec<u8>> {
     Ok(match p.as_rule() {
         // Data movement
-        Rule::LEA => todo!(),
+        Rule::LEA => return Err(unsupported(&p, current_file)),
         Rule::LINK => {
             let mut inner = p.into_inner();
             let an = inner
@@ -285,8 +884,17 @@ fn code_for_instr<'b, M: SymbolMap>(
                 .as_str()
                 .parse::<u8>()
                 .unwrap();
-            let data =
-                parse_expression(inner.next().unwrap().into_inner(), symbols, current_file)? as u16;
+            let data = inner.next().unwrap();
+            let span = data.as_span();
+            let data = parse_expression(data.into_inner(), symbols, current_file)?;
+            let data = in_range(
+                data,
+                SIGNED_WORD,
+                "a signed word displacement",
+                span,
+                current_file,
+                dry_run,
+            )? as u16;
             let opcode = 0b0100111001010000 | (an as u16);
             let mut res = opcode.to_be_bytes().to_vec();
             res.extend_from_slice(&data.to_be_bytes());
@@ -299,11 +907,26 @@ fn code_for_instr<'b, M: SymbolMap>(
                 .unwrap()
                 .into_inner()
                 .next()
-                .map(|p| int_size_to_enum(&p))
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
                 .unwrap_or_default();
-            let ((src_mode, src_reg, src_extra), (dst_mode, dst_reg, dst_extra)) = map_op_bin(
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file),
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file),
+            let ((src_mode, src_reg, src_extra), (dst_mode, dst_reg, dst_extra)) = map_code_bin(
+                get_mode_reg_extra_for_ea(
+                    inner.next().unwrap(),
+                    size,
+                    symbols,
+                    current_file,
+                    dry_run,
+                    warnings,
+                ),
+                get_mode_reg_extra_for_ea(
+                    inner.next().unwrap(),
+                    size,
+                    symbols,
+                    current_file,
+                    dry_run,
+                    warnings,
+                ),
                 |a, b| (a, b),
             )?;
             // let (src_mode, src_reg, src_extra) =
@@ -333,11 +956,18 @@ fn code_for_instr<'b, M: SymbolMap>(
                 .unwrap()
                 .into_inner()
                 .next()
-                .map(|p| int_size_to_enum(&p))
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
                 .unwrap_or_default();
 
-            let (src_mode, src_reg, src_extra) =
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file)?;
+            let (src_mode, src_reg, src_extra) = get_mode_reg_extra_for_ea(
+                inner.next().unwrap(),
+                size,
+                symbols,
+                current_file,
+                dry_run,
+                warnings,
+            )?;
             let reg_no: u16 = inner
                 .next()
                 .unwrap()
@@ -362,12 +992,70 @@ fn code_for_instr<'b, M: SymbolMap>(
             v.extend_from_slice(&src_extra);
             v
         }
-        Rule::MOVEM => todo!(),
+        Rule::MOVEM => {
+            let mut inner = p.into_inner();
+            let size = inner
+                .next()
+                .unwrap()
+                .into_inner()
+                .next()
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
+                .unwrap_or_default();
+            let first = inner.next().unwrap();
+            let second = inner.next().unwrap();
+            let (to_memory, list, ea) = match first.as_rule() {
+                Rule::reglist => (true, first, second),
+                // `MOVEM LABEL,LIST` is parsed as a register list followed by an absolute address
+                Rule::reglist_symbol
+                    if symbols.get_register_list(first.as_str()).is_none()
+                        && symbols
+                            .get_register_list(second.as_str().trim_end())
+                            .is_some() =>
+                {
+                    (false, second, first)
+                }
+                Rule::reglist_symbol => (true, first, second),
+                _ => (false, second, first),
+            };
+            let (mask, (mode, reg, extra)) = map_code_bin(
+                reglist_or_symbol_mask(list, symbols, current_file).map_err(CodeError::from),
+                if ea.as_rule() == Rule::reglist_symbol {
+                    symbols
+                        .get(ea.as_str())
+                        .map(|value| (0b111, 0b001, value.to_be_bytes().to_vec()))
+                        .ok_or_else(|| SymbolError::new(ea.as_span(), current_file).into())
+                } else {
+                    get_mode_reg_extra_for_ea(ea, size, symbols, current_file, dry_run, warnings)
+                },
+                |a, b| (a, b),
+            )?;
+            let mask = if mode == 0b100 {
+                mask.reverse_bits()
+            } else {
+                mask
+            };
+            #[allow(clippy::unusual_byte_groupings)]
+            let mut res = (0b01001_0_001_0_000_000u16
+                | (u16::from(!to_memory) << 10)
+                | (u16::from(size == IntSize::L) << 6)
+                | ((mode as u16) << 3)
+                | (reg as u16))
+                .to_be_bytes()
+                .to_vec();
+            res.extend_from_slice(&mask.to_be_bytes());
+            res.extend_from_slice(&extra);
+            res
+        }
         Rule::PEA => {
-            let size = IntSize::L;
             let mut inner = p.into_inner();
-            let (src_mode, src_reg, src_extra) =
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file)?;
+            let (src_mode, src_reg, src_extra) = get_mode_reg_extra_for_control_ea(
+                inner.next().unwrap(),
+                symbols,
+                current_file,
+                dry_run,
+                warnings,
+            )?;
             #[allow(clippy::unusual_byte_groupings)]
             let mut res = (0b0100100001_000_000u16 | (src_mode as u16) << 3 | (src_reg as u16))
                 .to_be_bytes()
@@ -389,7 +1077,7 @@ fn code_for_instr<'b, M: SymbolMap>(
             (0b0100111001011000u16 | (an as u16)).to_be_bytes().to_vec()
         }
         // Integer arithmetic
-        Rule::ADD => todo!(),
+        Rule::ADD => return Err(unsupported(&p, current_file)),
         Rule::ADDA => {
             let mut inner = p.into_inner();
             let size = inner
@@ -397,10 +1085,17 @@ fn code_for_instr<'b, M: SymbolMap>(
                 .unwrap()
                 .into_inner()
                 .next()
-                .map(|p| int_size_to_enum(&p))
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
                 .unwrap_or_default();
-            let (src_mode, src_reg, src_extra) =
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file)?;
+            let (src_mode, src_reg, src_extra) = get_mode_reg_extra_for_ea(
+                inner.next().unwrap(),
+                size,
+                symbols,
+                current_file,
+                dry_run,
+                warnings,
+            )?;
             let reg_no: u8 = inner
                 .next()
                 .unwrap()
@@ -423,7 +1118,7 @@ fn code_for_instr<'b, M: SymbolMap>(
             opcode.extend_from_slice(&src_extra);
             opcode
         }
-        Rule::ADDI => todo!(),
+        Rule::ADDI => return Err(unsupported(&p, current_file)),
         Rule::CLR => {
             let mut inner = p.into_inner();
             let size = inner
@@ -431,10 +1126,17 @@ fn code_for_instr<'b, M: SymbolMap>(
                 .unwrap()
                 .into_inner()
                 .next()
-                .map(|p| int_size_to_enum(&p))
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
                 .unwrap_or_default();
-            let (dst_mode, dst_reg, dst_extra) =
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file)?;
+            let (dst_mode, dst_reg, dst_extra) = get_mode_reg_extra_for_ea(
+                inner.next().unwrap(),
+                size,
+                symbols,
+                current_file,
+                dry_run,
+                warnings,
+            )?;
             let size = match size {
                 IntSize::B => 0b00,
                 IntSize::W => 0b01,
@@ -456,10 +1158,17 @@ fn code_for_instr<'b, M: SymbolMap>(
                 .unwrap()
                 .into_inner()
                 .next()
-                .map(|p| int_size_to_enum(&p))
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
                 .unwrap_or_default();
-            let (src_mode, src_reg, src_extra) =
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file)?;
+            let (src_mode, src_reg, src_extra) = get_mode_reg_extra_for_ea(
+                inner.next().unwrap(),
+                size,
+                symbols,
+                current_file,
+                dry_run,
+                warnings,
+            )?;
             let reg_no: u16 = inner
                 .next()
                 .unwrap()
@@ -478,7 +1187,7 @@ fn code_for_instr<'b, M: SymbolMap>(
             res.extend_from_slice(&src_extra);
             res
         }
-        Rule::CMPA => todo!(),
+        Rule::CMPA => return Err(unsupported(&p, current_file)),
         Rule::CMPI => {
             let mut inner = p.into_inner();
             let size = inner
@@ -486,12 +1195,19 @@ fn code_for_instr<'b, M: SymbolMap>(
                 .unwrap()
                 .into_inner()
                 .next()
-                .map(|p| int_size_to_enum(&p))
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
                 .unwrap_or_default();
-            let value =
-                parse_expression(inner.next().unwrap().into_inner(), symbols, current_file)?;
-            let (mode, reg, extra) =
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file)?;
+            let immediate =
+                immediate_extension(inner.next().unwrap(), size, symbols, current_file, warnings)?;
+            let (mode, reg, extra) = get_mode_reg_extra_for_ea(
+                inner.next().unwrap(),
+                size,
+                symbols,
+                current_file,
+                dry_run,
+                warnings,
+            )?;
             let bits_size = match size {
                 IntSize::B => 0b00,
                 IntSize::W => 0b01,
@@ -500,15 +1216,11 @@ fn code_for_instr<'b, M: SymbolMap>(
             let opcode =
                 0b0000110000000000u16 | (bits_size << 6) | ((mode as u16) << 3) | (reg as u16);
             let mut res = opcode.to_be_bytes().to_vec();
-            match size {
-                IntSize::B => res.extend_from_slice(&((value as u16) & 0xFF).to_be_bytes()),
-                IntSize::W => res.extend_from_slice(&(value as u16).to_be_bytes()),
-                IntSize::L => res.extend_from_slice(&(value as u32).to_be_bytes()),
-            }
+            res.extend_from_slice(&immediate);
             res.extend_from_slice(&extra);
             res
         }
-        Rule::SUB => todo!(),
+        Rule::SUB => return Err(unsupported(&p, current_file)),
         Rule::SUBA => {
             let mut inner = p.into_inner();
             let size = inner
@@ -516,10 +1228,17 @@ fn code_for_instr<'b, M: SymbolMap>(
                 .unwrap()
                 .into_inner()
                 .next()
-                .map(|p| int_size_to_enum(&p))
+                .map(|p| int_size_to_enum(&p, current_file))
+                .transpose()?
                 .unwrap_or_default();
-            let (src_mode, src_reg, src_extra) =
-                get_mode_reg_extra_for_ea(inner.next().unwrap(), size, symbols, current_file)?;
+            let (src_mode, src_reg, src_extra) = get_mode_reg_extra_for_ea(
+                inner.next().unwrap(),
+                size,
+                symbols,
+                current_file,
+                dry_run,
+                warnings,
+            )?;
             let reg_no: u8 = inner
                 .next()
                 .unwrap()
@@ -542,98 +1261,82 @@ fn code_for_instr<'b, M: SymbolMap>(
             opcode.extend_from_slice(&src_extra);
             opcode
         }
-        Rule::SUBI => todo!(),
+        Rule::SUBI => return Err(unsupported(&p, current_file)),
         // Program control
         Rule::Bcc => {
-            if dry_run {
-                vec![0, 0, 0, 0]
-            } else {
-                // println!("{p:#?}");
-                let mut inner = p.into_inner();
-                let (cc, _size) = {
-                    let mut mnemonic = inner.next().unwrap().into_inner();
-                    let cc = mnemonic.next().unwrap().as_str();
-                    (
-                        cc,
-                        mnemonic
-                            .next()
-                            .map(|p| int_size_to_enum(&p))
-                            .unwrap_or_default(),
-                    )
-                };
-                // HI High 0010 C Λ Z
-                // LS Low or Same 0011 C V Z
-                // CC(HI) Carry Clear 0100 C
-                // CS(LO) Carry Set 0101 C
-                // NE Not Equal 0110 Z
-                // EQ Equal 0111 Z
-                // VC Overflow Clear 1000 V
-                // VS Overflow Set 1001 V
-                // PL Plus 1010 N
-                // MI Minus 1011 N
-                // GE Greater or Equal 1100 N Λ V V N Λ V
-                // LT Less Than 1101 N Λ V V N Λ V
-                // GT Greater Than 1110 N Λ V Λ Z V N Λ V Λ Z
-                // LE Less or Equal 1111 Z V N Λ V V N Λ Vç
-                let cc = match cc.to_uppercase().as_str() {
-                    "HI" => 0b0010,
-                    "LS" => 0b0011,
-                    "CC" => 0b0100,
-                    "CS" => 0b0101,
-                    "NE" => 0b0110,
-                    "EQ" => 0b0111,
-                    "VC" => 0b1000,
-                    "VS" => 0b1001,
-                    "PL" => 0b1010,
-                    "MI" => 0b1011,
-                    "GE" => 0b1100,
-                    "LT" => 0b1101,
-                    "GT" => 0b1110,
-                    "LE" => 0b1111,
-                    x => unreachable!("Unexpected cc `{x}`"),
-                };
-                let symbol = inner.next().unwrap();
-                let symbol_value = symbols.get(symbol.as_str()).expect("label to jump to") as i32;
-                let disp = (((symbol_value - ((pc + 2) as i32)) as u32) as u16) as i16;
-                let mut res = (0b0110_0000_0000_0000u16 | cc << 8).to_be_bytes().to_vec();
-                res.extend_from_slice(&disp.to_be_bytes());
-                res
-            }
+            let mut inner = p.into_inner();
+            let mut mnemonic = inner.next().unwrap().into_inner();
+            let cc_pair = mnemonic.next().unwrap();
+            let cc = cc_pair.as_str();
+            let size = mnemonic
+                .next()
+                .map(|p| small_size_to_enum(&p, current_file))
+                .transpose()?
+                .unwrap_or(SmallSize::W);
+            // HI High 0010 C Λ Z
+            // LS Low or Same 0011 C V Z
+            // CC(HI) Carry Clear 0100 C
+            // CS(LO) Carry Set 0101 C
+            // NE Not Equal 0110 Z
+            // EQ Equal 0111 Z
+            // VC Overflow Clear 1000 V
+            // VS Overflow Set 1001 V
+            // PL Plus 1010 N
+            // MI Minus 1011 N
+            // GE Greater or Equal 1100 N Λ V V N Λ V
+            // LT Less Than 1101 N Λ V V N Λ V
+            // GT Greater Than 1110 N Λ V Λ Z V N Λ V Λ Z
+            // LE Less or Equal 1111 Z V N Λ V V N Λ Vç
+            let cc = match cc.to_uppercase().as_str() {
+                "HI" => 0b0010,
+                "LS" => 0b0011,
+                "CC" => 0b0100,
+                "CS" => 0b0101,
+                "NE" => 0b0110,
+                "EQ" => 0b0111,
+                "VC" => 0b1000,
+                "VS" => 0b1001,
+                "PL" => 0b1010,
+                "MI" => 0b1011,
+                "GE" => 0b1100,
+                "LT" => 0b1101,
+                "GT" => 0b1110,
+                "LE" => 0b1111,
+                _ => return Err(unexpected(&cc_pair, current_file)),
+            };
+            let target = inner.next().unwrap();
+            let span = target.as_span();
+            let promotable = promote_branches && size == SmallSize::W;
+            let target = branch_target(target, symbols, current_file, dry_run, promotable)?;
+            code_for_branch(cc, size, target, pc, span, current_file, promote_branches)?
         }
-        Rule::BRA => todo!(),
-        Rule::BSR => {
-            if dry_run {
-                vec![0, 0, 0, 0]
+        Rule::BRA | Rule::BSR => {
+            let cond = if p.as_rule() == Rule::BRA {
+                0b0000
             } else {
-                // let span = p.as_span();
-                let mut inner = p.into_inner();
-                let _ = inner
-                    .next()
-                    .unwrap()
-                    .into_inner()
-                    .next()
-                    .map(|p| small_size_to_enum(&p))
-                    .unwrap_or(SmallSize::W);
-                // println!("BSR {size:?}");
-                let symbol = inner.next().unwrap();
-                let symbol_value = symbols.get(symbol.as_str()).expect("label to jump to") as i32;
-                // println!("jump to {symbol_value:08X} from {pc:08X}");
-                let disp = (((symbol_value - ((pc + 2) as i32)) as u32) as u16) as i16;
-                // if size == SmallSize::B && (disp < i8::MIN as i16) && (disp > i8::MAX as i16) {
-                //     let pos = span.start_pos().line_col();
-                //     println!("[WARN] Cant do a byte bsr, label is too far away, using a word jump [{current_file}:{}:{}]", pos.0, pos.1);
-                //     disp -= 2;
-                // }else if size == SmallSize::W {
-                //     disp -= 2;
-                // }
-                // println!("DISP {disp}");
-                let mut opcode = 0b01100001_0000_0000u16.to_be_bytes().to_vec();
-                opcode.extend_from_slice(&disp.to_be_bytes());
-                // println!("OPCODE {opcode:02X?}");
-                opcode
-            }
+                0b0001
+            };
+            let mut inner = p.into_inner();
+            let size = inner
+                .next()
+                .unwrap()
+                .into_inner()
+                .next()
+                .map(|p| small_size_to_enum(&p, current_file))
+                .transpose()?
+                .unwrap_or(SmallSize::W);
+            let target = inner.next().unwrap();
+            let span = target.as_span();
+            let promotable = promote_branches && size == SmallSize::W;
+            let target = branch_target(target, symbols, current_file, dry_run, promotable)?;
+            code_for_branch(cond, size, target, pc, span, current_file, promote_branches)?
         }
-        Rule::JMP => {
+        Rule::JMP | Rule::JSR => {
+            let opcode = if p.as_rule() == Rule::JMP {
+                0b0100111011000000u16
+            } else {
+                0b0100111010000000
+            };
             let mut inner = p.into_inner();
             // let size = inner
             //     .next()
@@ -642,36 +1345,34 @@ fn code_for_instr<'b, M: SymbolMap>(
             //     .next()
             //     .map(|p| int_size_to_enum(&p))
             //     .unwrap_or_default();
-            let (src_mode, src_reg, src_extra) = get_mode_reg_extra_for_ea(
+            let (src_mode, src_reg, src_extra) = get_mode_reg_extra_for_control_ea(
                 inner.next().unwrap(),
-                IntSize::L,
                 symbols,
                 current_file,
+                dry_run,
+                warnings,
             )?;
             // let reg_no: u8 = inner.next().unwrap().into_inner().next().unwrap().as_str().parse().unwrap();
             // println!("JMP [{src_mode:03b} {src_reg:03b} {src_extra:02X?}]");
-            let mut bytes = (0b0100111011000000 | ((src_mode as u16) << 3) | (src_reg as u16))
+            let mut bytes = (opcode | ((src_mode as u16) << 3) | (src_reg as u16))
                 .to_be_bytes()
                 .to_vec();
             bytes.extend_from_slice(&src_extra);
             bytes
         }
-        Rule::JSR => todo!(),
         Rule::NOP => 0b0100111001110001u16.to_be_bytes().to_vec(),
         Rule::RTS => 0b0100111001110101u16.to_be_bytes().to_vec(),
         // System control
         Rule::ANDI_to_SR => {
-            let value = parse_expression(
-                p.into_inner().next().unwrap().into_inner(),
+            let mut res = 0b0000001001111100u16.to_be_bytes().to_vec();
+            res.extend_from_slice(&immediate_extension(
+                p.into_inner().next().unwrap(),
+                IntSize::W,
                 symbols,
                 current_file,
-            )? as u16;
-            vec![
-                0b00000010,
-                0b01111100,
-                (value >> 8) as u8,
-                (value & 0xff) as u8,
-            ]
+                warnings,
+            )?);
+            res
         }
         Rule::MOVE_to_SR => {
             let (src_mode, src_reg, src_extra) = get_mode_reg_extra_for_ea(
@@ -679,6 +1380,8 @@ fn code_for_instr<'b, M: SymbolMap>(
                 IntSize::W,
                 symbols,
                 current_file,
+                dry_run,
+                warnings,
             )?;
             let mut res = (0b0100011011000000u16 | ((src_mode as u16) << 3) | (src_reg as u16))
                 .to_be_bytes()
@@ -717,33 +1420,61 @@ fn code_for_instr<'b, M: SymbolMap>(
             let mut inner = p.into_inner();
             let vector = inner
                 .next()
-                .map(|x| parse_expression(x.into_inner(), symbols, current_file))
+                .map(|x| {
+                    let span = x.as_span();
+                    parse_expression(x.into_inner(), symbols, current_file).and_then(|vector| {
+                        in_range(
+                            vector,
+                            0..=7,
+                            "a breakpoint vector",
+                            span,
+                            current_file,
+                            dry_run,
+                        )
+                    })
+                })
                 .transpose()?
                 .unwrap_or(0) as u32;
             (0b0100100001001000 | ((vector & 0b111) as u16))
                 .to_be_bytes()
                 .to_vec()
         }
+        #[allow(clippy::unusual_byte_groupings)]
         Rule::TRAP => {
-            let value = parse_expression(
-                p.into_inner().next().unwrap().into_inner(),
-                symbols,
-                current_file,
-            )? as u32;
+            let value = p.into_inner().next().unwrap();
+            let span = value.as_span();
+            let value = parse_expression(value.into_inner(), symbols, current_file)?;
+            let value =
+                in_range(value, 0..=15, "a trap vector", span, current_file, dry_run)? as u32;
             (0b010011100100_0000 | ((value & 0b1111) as u16))
                 .to_be_bytes()
                 .to_vec()
         }
-        x => unreachable!("{x:?}"),
+        _ => return Err(unsupported(&p, current_file)),
     })
 }
 
 pub fn statements(program: Pairs<Rule>) -> impl Iterator<Item = Pair<Rule>> + Clone {
-    program.flat_map(|x| x.into_inner()).filter_map(|pair| {
-        if pair.as_rule() == Rule::statement {
-            pair.into_inner().next()
-        } else {
-            None
-        }
-    })
+    program
+        .flat_map(|x| x.into_inner())
+        .filter_map(|pair| match pair.as_rule() {
+            Rule::statement => pair.into_inner().next(),
+            Rule::end | Rule::ignored => Some(pair),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::ASMParser;
+    use pest::Parser;
+
+    #[test]
+    fn test_reglist_mask() {
+        let mask = |s| reglist_mask(ASMParser::parse(Rule::reglist, s).unwrap().next().unwrap());
+        assert_eq!(mask("D2-D7/A2-A6"), 0x7CFC);
+        assert_eq!(mask("D0/D3/A1"), 0x0209);
+        assert_eq!(mask("A6-A2"), 0x7C00);
+    }
 }
@@ -0,0 +1,179 @@
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Code,
+    Data,
+    /// Uninitialised storage, only reserves space and is not part of the S-Record
+    Bss,
+}
+
+impl SectionKind {
+    pub fn from_str_ignore_case(s: &str) -> Option<Self> {
+        match s.to_uppercase().as_str() {
+            "CODE" => Some(Self::Code),
+            "DATA" => Some(Self::Data),
+            "BSS" => Some(Self::Bss),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SectionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Code => write!(f, "CODE"),
+            Self::Data => write!(f, "DATA"),
+            Self::Bss => write!(f, "BSS"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Section<'a> {
+    pub name: &'a str,
+    pub kind: SectionKind,
+    /// Address where the section starts if it has no `ORG` of its own
+    base: u32,
+    /// Location counter
+    pub pc: u32,
+    /// Highest address reached by the section in the current pass
+    end: u32,
+    /// The section started with an `ORG`, so it is not placed after the previous one
+    fixed: bool,
+    /// Indexes on the code object of the code generated for this section
+    pub chunks: Vec<usize>,
+}
+
+impl<'a> Section<'a> {
+    const fn new(name: &'a str, kind: SectionKind) -> Self {
+        Self {
+            name,
+            kind,
+            base: 0,
+            pc: 0,
+            end: 0,
+            fixed: false,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Sets the location counter, as done by `ORG`
+    pub fn org(&mut self, addr: u32) {
+        if self.pc == self.base && self.end == self.base {
+            self.fixed = true;
+        }
+        self.pc = addr;
+        self.end = self.end.max(addr);
+    }
+
+    /// Bytes used to fill `len` bytes from `addr`: NOPs on CODE sections (once aligned) and zeros otherwise.
+    ///
+    /// BSS sections are not initialised, so no bytes are generated.
+    pub fn padding(&self, addr: u32, len: u32) -> Vec<u8> {
+        match self.kind {
+            SectionKind::Bss => vec![],
+            SectionKind::Data => vec![0; len as usize],
+            SectionKind::Code => {
+                let mut res = Vec::with_capacity(len as usize);
+                if addr % 2 == 1 && len > 0 {
+                    res.push(0);
+                }
+                while len as usize - res.len() >= 2 {
+                    res.extend_from_slice(&0b0100111001110001u16.to_be_bytes());
+                }
+                res.resize(len as usize, 0);
+                res
+            }
+        }
+    }
+
+    /// Moves the location counter to `addr` and reserves `len` bytes after it, `None` if they go past
+    /// the last address
+    pub fn advance(&mut self, addr: u32, len: u32) -> Option<()> {
+        self.pc = addr.checked_add(len)?;
+        self.end = self.end.max(self.pc);
+        Some(())
+    }
+}
+
+/// Sections of the program, each one with its own location counter.
+///
+/// The first one is the default `CODE` section, which starts at 0.
+#[derive(Debug, Clone)]
+pub struct Sections<'a> {
+    sections: Vec<Section<'a>>,
+    current: usize,
+}
+
+impl<'a> Sections<'a> {
+    pub fn new() -> Self {
+        Self {
+            sections: vec![Section::new("CODE", SectionKind::Code)],
+            current: 0,
+        }
+    }
+
+    /// Resets the location counters to the start of each section
+    pub fn start_pass(&mut self) {
+        self.current = 0;
+        for section in &mut self.sections {
+            section.pc = section.base;
+            section.end = section.base;
+            section.fixed = false;
+        }
+    }
+
+    /// Switches to the section `name`, creating it if it did not exist.
+    ///
+    /// If the section already exists with a different kind, the kind it has is returned as the error.
+    pub fn switch(&mut self, name: &'a str, kind: Option<SectionKind>) -> Result<(), SectionKind> {
+        if let Some(idx) = self.sections.iter().position(|s| s.name == name) {
+            let section = &self.sections[idx];
+            if kind.map(|kind| kind != section.kind).unwrap_or(false) {
+                return Err(section.kind);
+            }
+            self.current = idx;
+        } else {
+            self.sections
+                .push(Section::new(name, kind.unwrap_or(SectionKind::Code)));
+            self.current = self.sections.len() - 1;
+        }
+        Ok(())
+    }
+
+    pub const fn current_idx(&self) -> usize {
+        self.current
+    }
+
+    pub fn current(&mut self) -> &mut Section<'a> {
+        &mut self.sections[self.current]
+    }
+
+    /// Places every section that does not start with an `ORG` right after the previous one.
+    ///
+    /// Returns whether any section moved, in which case another pass is needed.
+    pub fn layout(&mut self) -> bool {
+        let mut changed = false;
+        let mut next = 0u32;
+        for section in &mut self.sections {
+            if !section.fixed {
+                let base = next + next % 2;
+                changed |= base != section.base;
+                section.base = base;
+            }
+            next = section.end.max(section.base);
+        }
+        changed
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Section<'a>> {
+        self.sections.iter()
+    }
+}
+
+impl<'a> Default for Sections<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
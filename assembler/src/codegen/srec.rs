@@ -115,7 +115,12 @@ pub enum Record<'a> {
     S1(u16, SliceMaybeOwned<'a, u8>),
     S2(u32, SliceMaybeOwned<'a, u8>), // 24 byte addr
     S3(u32, SliceMaybeOwned<'a, u8>),
-    S9,
+    /// Termination with a 32 bit start address
+    S7(u32),
+    /// Termination with a 24 bit start address
+    S8(u32),
+    /// Termination with a 16 bit start address
+    S9(u16),
 }
 
 impl<'a> Record<'a> {
@@ -129,6 +134,17 @@ impl<'a> Record<'a> {
         }
     }
 
+    /// Termination record for the start address `addr`, as narrow as the address allows
+    pub const fn termination(addr: u32) -> Self {
+        if addr <= 0xffff {
+            Self::S9(addr as u16)
+        } else if addr <= 0xff_ffff {
+            Self::S8(addr)
+        } else {
+            Self::S7(addr)
+        }
+    }
+
     pub fn split_max_len(self) -> Box<dyn Iterator<Item = Record<'a>> + 'a> {
         match self {
             Record::S1(addr, code) => {
@@ -215,7 +231,14 @@ impl Display for Record<'_> {
                 code.iter().map(|x| format!("{x:02X}")).collect::<String>(),
                 checksum(code, 4, *addr as usize)
             ),
-            Record::S9 => write!(f, "S9030000FC"),
+            Record::S7(addr) => write!(f, "S705{addr:08X}{:02X}", checksum(&[], 4, *addr as usize)),
+            Record::S8(addr) => write!(
+                f,
+                "S804{:06X}{:02X}",
+                *addr & 0xffffff,
+                checksum(&[], 3, (*addr & 0xffffff) as usize)
+            ),
+            Record::S9(addr) => write!(f, "S903{addr:04X}{:02X}", checksum(&[], 2, *addr as usize)),
         }
     }
 }
@@ -223,7 +246,7 @@ impl Display for Record<'_> {
 pub struct SRec<'a>(Vec<Record<'a>>);
 
 impl<'a> SRec<'a> {
-    pub fn new(code: impl Iterator<Item = (u32, &'a [u8])>) -> Self {
+    pub fn new(code: impl Iterator<Item = (u32, &'a [u8])>, start_addr: u32) -> Self {
         let mut res = Vec::with_capacity(code.size_hint().0 + 2);
         res.push(Record::S0);
         let mut pc = None;
@@ -248,7 +271,7 @@ impl<'a> SRec<'a> {
             pc = Some(addr + code.len() as u32)
         }
         add_last_rec(&mut last_rec, None);
-        res.push(Record::S9);
+        res.push(Record::termination(start_addr));
         Self(res)
     }
 }
@@ -261,3 +284,19 @@ impl Display for SRec<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_termination() {
+        assert_eq!(Record::termination(0).to_string(), "S9030000FC");
+        assert_eq!(Record::termination(0x2000).to_string(), "S9032000DC");
+        assert_eq!(Record::termination(0x12_3456).to_string(), "S8041234565F");
+        assert_eq!(
+            Record::termination(0x1234_5678).to_string(),
+            "S70512345678E6"
+        );
+    }
+}
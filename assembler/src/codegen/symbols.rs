@@ -1,7 +1,146 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, fmt::Display, path::Path};
+
+/// Symbol that holds the address of the statement being assembled, used by `*` on expressions
+pub const CURRENT_LOCATION: &str = "*";
+/// Symbol that holds the counter of `RS`, which gives the size of the structure laid out so far
+pub const RS_COUNTER: &str = "__RS";
+
+/// Whether `name` is one of the symbols defined by the assembler itself
+pub fn is_internal(name: &str) -> bool {
+    name == CURRENT_LOCATION || name == RS_COUNTER
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    Equ,
+    /// Defined with `REG`, the value is the register mask (bit 0 is D0 and bit 15 is A7)
+    RegisterList,
+    /// Defined with `SET`, it can be redefined with another `SET`
+    Set,
+    /// Defined on the command line with `-D`
+    Define,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub value: u32,
+    /// Index of the section the symbol was defined in, `None` if absolute
+    pub section: Option<usize>,
+    pub kind: SymbolKind,
+    /// Bytes generated or reserved by the statement that defined it
+    pub size: u32,
+}
+
+/// How symbol names are compared
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NameRules {
+    pub case_insensitive: bool,
+    /// Only the first characters are significant, the rest are ignored
+    pub significant_length: Option<usize>,
+}
+
+impl NameRules {
+    /// Names are compared as they are written
+    pub fn is_exact(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Name that is the same for every spelling of the symbol
+    pub fn canonical<'s>(&self, name: &'s str) -> Cow<'s, str> {
+        let name = match self.significant_length {
+            Some(len) => name
+                .char_indices()
+                .nth(len)
+                .map_or(name, |(i, _)| &name[..i]),
+            None => name,
+        };
+        if self.case_insensitive {
+            Cow::Owned(name.to_uppercase())
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+}
+
+impl Display for NameRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.case_insensitive, self.significant_length) {
+            (true, Some(len)) => write!(
+                f,
+                "Symbols are case insensitive and only their first {len} characters are significant"
+            ),
+            (true, None) => write!(f, "Symbols are case insensitive"),
+            (false, Some(len)) => write!(
+                f,
+                "Only the first {len} characters of symbols are significant"
+            ),
+            (false, None) => write!(f, "Symbols are compared as they are written"),
+        }
+    }
+}
+
+/// Spelling of the first definition of each symbol, which is the one used as its key
+#[derive(Debug, Clone, Default)]
+pub struct SymbolNames<'a> {
+    pub rules: NameRules,
+    spellings: HashMap<String, &'a str>,
+}
+
+impl<'a> SymbolNames<'a> {
+    pub fn new(rules: NameRules) -> Self {
+        Self {
+            rules,
+            spellings: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.spellings.clear();
+    }
+
+    /// Key of the symbol `name`, or `name` itself if it is not defined
+    pub fn key<'s>(&self, name: &'s str) -> &'s str
+    where
+        'a: 's,
+    {
+        if self.rules.is_exact() {
+            return name;
+        }
+        self.spellings
+            .get(self.rules.canonical(name).as_ref())
+            .copied()
+            .unwrap_or(name)
+    }
+
+    /// Key for a new definition of `name`, and whether it is spelled differently than the first one
+    pub fn insert(&mut self, name: &'a str) -> (&'a str, bool) {
+        if self.rules.is_exact() {
+            return (name, false);
+        }
+        let key = *self
+            .spellings
+            .entry(self.rules.canonical(name).into_owned())
+            .or_insert(name);
+        (key, key != name)
+    }
+}
 
 pub trait SymbolMap {
+    /// Value of a symbol usable on expressions
     fn get(&self, s: &str) -> Option<u32>;
+    /// Register mask of a register list symbol
+    fn get_register_list(&self, s: &str) -> Option<u16>;
+    /// Size of the statement that defined a symbol
+    fn get_size(&self, s: &str) -> Option<u32>;
+    /// Value the symbol had at the end of the previous pass, an estimate for the ones not defined yet
+    fn get_previous(&self, _s: &str) -> Option<u32> {
+        None
+    }
+    /// Whether the symbol is defined at this point of the source, for `DEF`
+    fn is_defined(&self, s: &str) -> bool {
+        self.get(s).is_some() || self.get_register_list(s).is_some()
+    }
     type Failing: SymbolMap;
     fn get_failing(&self) -> &Self::Failing;
 }
@@ -22,11 +161,28 @@ pub struct NonFailingMap<M>(pub M);
 //     }
 // }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'a, M: SymbolMap> SymbolMap for NonFailingMap<&'a M> {
     fn get(&self, s: &str) -> Option<u32> {
         Some(self.0.get(s).unwrap_or(0))
     }
 
+    fn get_register_list(&self, s: &str) -> Option<u16> {
+        Some(self.0.get_register_list(s).unwrap_or(0))
+    }
+
+    fn get_size(&self, s: &str) -> Option<u32> {
+        Some(self.0.get_size(s).unwrap_or(0))
+    }
+
+    fn get_previous(&self, s: &str) -> Option<u32> {
+        self.0.get_previous(s)
+    }
+
+    fn is_defined(&self, s: &str) -> bool {
+        self.0.is_defined(s)
+    }
+
     type Failing = M;
 
     fn get_failing(&self) -> &Self::Failing {
@@ -40,9 +196,21 @@ impl<'a, M: SymbolMap> SymbolMap for NonFailingMap<&'a M> {
 //     }
 // }
 
-impl<'a> SymbolMap for HashMap<&'a str, u32> {
+impl SymbolMap for HashMap<&str, Symbol> {
     fn get(&self, s: &str) -> Option<u32> {
-        self.get(s).copied()
+        self.get(s)
+            .filter(|symbol| symbol.kind != SymbolKind::RegisterList)
+            .map(|symbol| symbol.value)
+    }
+
+    fn get_register_list(&self, s: &str) -> Option<u16> {
+        self.get(s)
+            .filter(|symbol| symbol.kind == SymbolKind::RegisterList)
+            .map(|symbol| symbol.value as u16)
+    }
+
+    fn get_size(&self, s: &str) -> Option<u32> {
+        self.get(s).map(|symbol| symbol.size)
     }
 
     type Failing = Self;
@@ -57,6 +225,88 @@ impl SymbolMap for HashMap<String, u32> {
         self.get(s).copied()
     }
 
+    fn get_register_list(&self, _: &str) -> Option<u16> {
+        None
+    }
+
+    fn get_size(&self, _: &str) -> Option<u32> {
+        None
+    }
+
+    type Failing = Self;
+
+    fn get_failing(&self) -> &Self::Failing {
+        self
+    }
+}
+
+/// Files and lines where each symbol is used
+pub type References<'a> = HashMap<&'a str, Vec<(&'a Path, usize)>>;
+
+/// Symbols of the source that records where they are used, for the cross-reference table
+pub struct ReferencingMap<'m, 'a> {
+    pub symbols: &'m HashMap<&'a str, Symbol>,
+    pub names: &'m SymbolNames<'a>,
+    /// Line being assembled, `None` if the uses are not recorded
+    pub location: Option<(&'a Path, usize)>,
+    pub references: &'m RefCell<References<'a>>,
+    /// Values of the previous pass, by the name given by the `NameRules`
+    pub previous: &'m HashMap<String, u32>,
+    /// Statement that defined each symbol of the source
+    pub definition_order: &'m HashMap<&'a str, usize>,
+    /// Statement being assembled
+    pub statement: usize,
+}
+
+impl<'m, 'a> ReferencingMap<'m, 'a> {
+    fn record<T>(&self, s: &str, value: Option<T>) -> Option<T> {
+        if let (Some(location), Some((&name, _)), Some(_)) =
+            (self.location, self.symbols.get_key_value(s), &value)
+        {
+            if !is_internal(name) {
+                let mut references = self.references.borrow_mut();
+                let uses = references.entry(name).or_default();
+                if uses.last() != Some(&location) {
+                    uses.push(location);
+                }
+            }
+        }
+        value
+    }
+}
+
+impl SymbolMap for ReferencingMap<'_, '_> {
+    fn get(&self, s: &str) -> Option<u32> {
+        let s = self.names.key(s);
+        self.record(s, SymbolMap::get(self.symbols, s))
+    }
+
+    fn get_register_list(&self, s: &str) -> Option<u16> {
+        let s = self.names.key(s);
+        self.record(s, self.symbols.get_register_list(s))
+    }
+
+    fn get_size(&self, s: &str) -> Option<u32> {
+        let s = self.names.key(s);
+        self.record(s, self.symbols.get_size(s))
+    }
+
+    fn get_previous(&self, s: &str) -> Option<u32> {
+        self.previous
+            .get(self.names.rules.canonical(s).as_ref())
+            .copied()
+    }
+
+    /// The last pass starts with every symbol, so the ones of the source are only defined after their statement
+    fn is_defined(&self, s: &str) -> bool {
+        let s = self.names.key(s);
+        let defined = match self.definition_order.get(s) {
+            Some(&statement) => statement < self.statement,
+            None => self.symbols.contains_key(s),
+        };
+        self.record(s, defined.then_some(())).is_some()
+    }
+
     type Failing = Self;
 
     fn get_failing(&self) -> &Self::Failing {
@@ -0,0 +1,258 @@
+use std::{fmt::Display, path::Path};
+
+use crate::{
+    error::{ErrorKind, SpanErrorDisplay},
+    utils::JsonStr,
+};
+
+const fn severity(kind: &ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::Error => "error",
+        ErrorKind::Warning => "warning",
+        ErrorKind::Info => "info",
+    }
+}
+
+/// Every diagnostic as a JSON document, for editors and other tools
+pub struct JsonDiagnostics<'b, 'a>(pub &'b [SpanErrorDisplay<'a>]);
+
+impl Display for JsonDiagnostics<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{{")?;
+        writeln!(f, "  \"diagnostics\": [")?;
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            write!(
+                f,
+                "    {{\"severity\": \"{}\", \"code\": {}, \"message\": {}, \"file\": {}, ",
+                severity(&diagnostic.kind),
+                JsonStr(&diagnostic.code),
+                JsonStr(&diagnostic.message),
+                JsonStr(&diagnostic.file.path.to_string_lossy()),
+            )?;
+            match diagnostic.range() {
+                Some(((start_line, start_column), (end_line, end_column))) => write!(
+                    f,
+                    "\"start\": {{\"line\": {start_line}, \"column\": {start_column}}}, \
+                     \"end\": {{\"line\": {end_line}, \"column\": {end_column}}}, "
+                )?,
+                None => write!(f, "\"start\": null, \"end\": null, ")?,
+            }
+            write!(f, "\"notes\": [")?;
+            if let Some(note) = &diagnostic.note {
+                write!(f, "{}", JsonStr(note))?;
+            }
+            write!(f, "], \"included_from\": [")?;
+            for (j, (path, line)) in diagnostic.included_from.iter().enumerate() {
+                let separator = if j == 0 { "" } else { ", " };
+                write!(
+                    f,
+                    "{separator}{{\"file\": {}, \"line\": {line}}}",
+                    JsonStr(&path.to_string_lossy())
+                )?;
+            }
+            let separator = if i + 1 < self.0.len() { "," } else { "" };
+            writeln!(f, "]}}{separator}")?;
+        }
+        writeln!(f, "  ]")?;
+        writeln!(f, "}}")
+    }
+}
+
+/// `path` as the URI of a SARIF artifact: a relative reference if it is relative, or a `file` URI
+/// if it is absolute, also for the paths of Windows
+fn artifact_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let bytes = path.as_bytes();
+    let (mut uri, rest) = if bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && bytes[2] == b'/'
+    {
+        // The colon of a drive letter is kept, it would be taken for a scheme on a relative reference
+        (format!("file:///{}", &path[..2]), &path[2..])
+    } else if path.starts_with("//") {
+        // A network share, whose server is the authority
+        ("file:".to_owned(), path.as_str())
+    } else if path.starts_with('/') {
+        ("file://".to_owned(), path.as_str())
+    } else {
+        (String::new(), path.as_str())
+    };
+    for b in rest.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{b:02X}"));
+        }
+    }
+    uri
+}
+
+/// Region of a range of lines and columns, where the end is exclusive
+fn region(
+    ((start_line, start_column), (end_line, end_column)): ((usize, usize), (usize, usize)),
+) -> String {
+    format!(
+        "{{\"startLine\": {start_line}, \"startColumn\": {start_column}, \
+         \"endLine\": {end_line}, \"endColumn\": {end_column}}}"
+    )
+}
+
+/// Location in `path`, on `region` if it is not about the whole file
+fn write_physical_location(
+    f: &mut std::fmt::Formatter<'_>,
+    path: &Path,
+    region: Option<String>,
+) -> std::fmt::Result {
+    write!(
+        f,
+        "\"physicalLocation\": {{\"artifactLocation\": {{\"uri\": {}}}",
+        JsonStr(&artifact_uri(path)),
+    )?;
+    if let Some(region) = region {
+        write!(f, ", \"region\": {region}")?;
+    }
+    write!(f, "}}")
+}
+
+/// Every diagnostic as a SARIF 2.1.0 log, for code scanning dashboards
+pub struct SarifDiagnostics<'b, 'a>(pub &'b [SpanErrorDisplay<'a>]);
+
+impl Display for SarifDiagnostics<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut rules = self
+            .0
+            .iter()
+            .map(|diagnostic| diagnostic.code.as_ref())
+            .collect::<Vec<_>>();
+        rules.sort_unstable();
+        rules.dedup();
+        writeln!(f, "{{")?;
+        writeln!(
+            f,
+            "  \"$schema\": \"https://json.schemastore.org/sarif-2.1.0.json\","
+        )?;
+        writeln!(f, "  \"version\": \"2.1.0\",")?;
+        writeln!(f, "  \"runs\": [")?;
+        writeln!(f, "    {{")?;
+        writeln!(f, "      \"tool\": {{")?;
+        writeln!(f, "        \"driver\": {{")?;
+        writeln!(f, "          \"name\": \"p68kasm\",")?;
+        writeln!(
+            f,
+            "          \"version\": \"{}\",",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        write!(f, "          \"rules\": [")?;
+        for (i, rule) in rules.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{separator}{{\"id\": {}}}", JsonStr(rule))?;
+        }
+        writeln!(f, "]")?;
+        writeln!(f, "        }}")?;
+        writeln!(f, "      }},")?;
+        writeln!(f, "      \"results\": [")?;
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            let level = match diagnostic.kind {
+                ErrorKind::Error => "error",
+                ErrorKind::Warning => "warning",
+                ErrorKind::Info => "note",
+            };
+            write!(
+                f,
+                "        {{\"ruleId\": {}, \"level\": \"{level}\", \"message\": {{\"text\": {}}}, ",
+                JsonStr(&diagnostic.code),
+                JsonStr(&diagnostic.message),
+            )?;
+            write!(f, "\"locations\": [{{")?;
+            write_physical_location(f, diagnostic.file.path, diagnostic.range().map(region))?;
+            write!(f, "}}]")?;
+            // The note is on the place of the diagnostic, and each include on the line that includes
+            let related = diagnostic
+                .note
+                .iter()
+                .map(|note| {
+                    (
+                        note.as_ref(),
+                        diagnostic.file.path,
+                        diagnostic.range().map(region),
+                    )
+                })
+                .chain(diagnostic.included_from.iter().map(|&(path, line)| {
+                    (
+                        "included from here",
+                        path,
+                        Some(format!("{{\"startLine\": {line}}}")),
+                    )
+                }));
+            write!(f, ", \"relatedLocations\": [")?;
+            for (id, (text, path, region)) in related.enumerate() {
+                let separator = if id == 0 { "" } else { ", " };
+                write!(
+                    f,
+                    "{separator}{{\"id\": {id}, \"message\": {{\"text\": {}}}, ",
+                    JsonStr(text)
+                )?;
+                write_physical_location(f, path, region)?;
+                write!(f, "}}")?;
+            }
+            write!(f, "]}}")?;
+            let separator = if i + 1 < self.0.len() { "," } else { "" };
+            writeln!(f, "{separator}")?;
+        }
+        writeln!(f, "      ]")?;
+        writeln!(f, "    }}")?;
+        writeln!(f, "  ]")?;
+        writeln!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pest::Span;
+
+    use super::*;
+    use crate::file::FileRef;
+
+    /// An error on `BRA nowhere` with a note, in a file included from line 3 of `main.s`
+    fn diagnostic() -> SpanErrorDisplay<'static> {
+        let source = "\tBRA nowhere\n";
+        let file = FileRef::new(Path::new("inc.s"), source);
+        SpanErrorDisplay::new(
+            Span::new(source, 5, 12).unwrap(),
+            file,
+            "symbol `nowhere` is undefined".into(),
+        )
+        .with_code("undefined-symbol")
+        .with_note("Did you mean `there`?")
+        .with_included_from(&vec![(Path::new("main.s"), 3)])
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            JsonDiagnostics(&[diagnostic()]).to_string(),
+            "{\n  \"diagnostics\": [\n    {\"severity\": \"error\", \"code\": \"undefined-symbol\", \"message\": \"symbol `nowhere` is undefined\", \"file\": \"inc.s\", \"start\": {\"line\": 1, \"column\": 6}, \"end\": {\"line\": 1, \"column\": 13}, \"notes\": [\"Did you mean `there`?\"], \"included_from\": [{\"file\": \"main.s\", \"line\": 3}]}\n  ]\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_sarif() {
+        let sarif = SarifDiagnostics(&[diagnostic()]).to_string();
+        // The message is the same as on the other formats
+        assert!(sarif.contains(r#""message": {"text": "symbol `nowhere` is undefined"}, "locations": [{"physicalLocation": {"artifactLocation": {"uri": "inc.s"}, "region": {"startLine": 1, "startColumn": 6, "endLine": 1, "endColumn": 13}}}]"#));
+        assert!(sarif.contains(r#""relatedLocations": [{"id": 0, "message": {"text": "Did you mean `there`?"}, "physicalLocation": {"artifactLocation": {"uri": "inc.s"}, "region": {"startLine": 1, "startColumn": 6, "endLine": 1, "endColumn": 13}}}, {"id": 1, "message": {"text": "included from here"}, "physicalLocation": {"artifactLocation": {"uri": "main.s"}, "region": {"startLine": 3}}}]"#));
+        assert!(sarif.contains(r#""rules": [{"id": "undefined-symbol"}]"#));
+    }
+
+    #[test]
+    fn test_artifact_uri() {
+        let uri = |path: &str| artifact_uri(Path::new(path));
+        assert_eq!(uri("src/main file.s"), "src/main%20file.s");
+        assert_eq!(uri("a:b.s/x#1.s"), "a%3Ab.s/x%231.s");
+        assert_eq!(uri("inc/c:d.s"), "inc/c%3Ad.s");
+        assert_eq!(uri("/home/me/100%.s"), "file:///home/me/100%25.s");
+        assert_eq!(uri("C:\\Users\\me\\a.s"), "file:///C:/Users/me/a.s");
+        assert_eq!(uri("\\\\server\\share\\a.s"), "file://server/share/a.s");
+    }
+}
@@ -1,19 +1,133 @@
-use std::{borrow::Cow, fmt::Display};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
+use clap::ValueEnum;
 use pest::{error::InputLocation, Position, Span};
 use thiserror::Error;
 
-use crate::{file::FileRef, parser::Rule, utils::PrintIteratorSep};
+use crate::{
+    codegen::section::SectionKind,
+    file::{FileRef, IncludeStack},
+    parser::Rule,
+    suggest,
+    utils::PrintIteratorSep,
+};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolErrorKind {
+    Undefined,
+    /// A register list used where a value was expected
+    RegisterList,
+    /// A symbol used where a register list was expected
+    NotRegisterList,
+    /// The right side of a `/` or `\\` is 0, the location is the operator
+    DivisionByZero,
+    /// Shift by the given number of bits, which is not between 0 and 31, the location is the operator
+    ShiftOverflow(i64),
+    /// A number that does not fit in 32 bits
+    LiteralOverflow,
+    /// A symbol exported with `XDEF` that is never defined
+    UndefinedExport,
+    /// A value that does not fit in the field of the instruction, described by `field`
+    OutOfRange {
+        value: i64,
+        field: &'static str,
+        min: i64,
+        max: i64,
+    },
+    /// A branch whose target is `distance` bytes away from the end of its opcode, which is too far
+    /// for a byte displacement if it is `short`, or for a word one
+    BranchOutOfRange {
+        distance: i64,
+        short: bool,
+    },
+}
+
+impl SymbolErrorKind {
+    /// Name that identifies the error on machine readable diagnostics
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::Undefined => "undefined-symbol",
+            Self::RegisterList => "register-list",
+            Self::NotRegisterList => "not-register-list",
+            Self::DivisionByZero => "division-by-zero",
+            Self::ShiftOverflow(_) => "shift-overflow",
+            Self::LiteralOverflow => "literal-overflow",
+            Self::UndefinedExport => "undefined-export",
+            Self::OutOfRange { .. } => "out-of-range",
+            Self::BranchOutOfRange { .. } => "branch-out-of-range",
+        }
+    }
+}
+
+impl Display for SymbolErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Undefined => write!(f, "symbol undefined"),
+            Self::RegisterList => write!(f, "register list used as a value"),
+            Self::NotRegisterList => write!(f, "symbol is not a register list"),
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::ShiftOverflow(bits) => write!(f, "shift by {bits} bits"),
+            Self::LiteralOverflow => write!(f, "number does not fit in 32 bits"),
+            Self::UndefinedExport => write!(f, "exported symbol undefined"),
+            Self::OutOfRange { value, field, .. } => write!(f, "{value} does not fit in {field}"),
+            Self::BranchOutOfRange { distance, .. } => {
+                write!(f, "branch target {distance} bytes away")
+            }
+        }
+    }
+}
+
+/// Error on a symbol or other part of an expression
 #[derive(Debug, Error)]
 pub struct SymbolError<'a> {
     symbol_loc: Span<'a>,
     file: FileRef<'a>,
+    kind: SymbolErrorKind,
+    /// Defined symbol that an undefined one may be a typo of
+    suggestion: Option<&'a str>,
+    /// On an expression that the layout depends on, which is evaluated when its line is reached
+    in_layout: bool,
 }
 
 impl<'a> SymbolError<'a> {
     pub const fn new(symbol_loc: Span<'a>, file: FileRef<'a>) -> Self {
-        Self { symbol_loc, file }
+        Self::with_kind(symbol_loc, file, SymbolErrorKind::Undefined)
+    }
+
+    pub const fn with_kind(symbol_loc: Span<'a>, file: FileRef<'a>, kind: SymbolErrorKind) -> Self {
+        Self {
+            symbol_loc,
+            file,
+            kind,
+            suggestion: None,
+            in_layout: false,
+        }
+    }
+
+    /// Marks the error as coming from an expression of `ORG`, `EQU`, `DS` or an alignment, whose
+    /// symbols have to be defined before it
+    pub fn in_layout(mut self) -> Self {
+        self.in_layout = true;
+        self
+    }
+
+    /// Suggests the closest of `names` if the symbol is undefined, as it may be misspelled
+    pub fn suggest(&mut self, names: &[&'a str]) {
+        let symbol = self.symbol_loc.as_str();
+        let undefined = matches!(
+            self.kind,
+            SymbolErrorKind::Undefined | SymbolErrorKind::UndefinedExport
+        );
+        // Defined after it is needed, which is explained by the note of the error
+        if undefined && !names.contains(&symbol) {
+            self.suggestion = suggest::closest(symbol, names.iter().copied());
+        }
     }
 }
 
@@ -22,7 +136,8 @@ impl Display for SymbolError<'_> {
         let (line, col) = self.symbol_loc.start_pos().line_col();
         write!(
             f,
-            "symbol undefined [{}] @ {}:{line}:{col}",
+            "{} [{}] @ {}:{line}:{col}",
+            self.kind,
             self.symbol_loc.as_str(),
             self.file.path.display(),
         )
@@ -31,9 +146,9 @@ impl Display for SymbolError<'_> {
 
 pub type SymbolResult<'a, T> = Result<T, Vec<SymbolError<'a>>>;
 
-pub fn map_op_bin<'a, T, U, F: FnOnce(T, T) -> U>(
+pub fn map_op_bin<'a, T, V, U, F: FnOnce(T, V) -> U>(
     lhs: SymbolResult<'a, T>,
-    rhs: SymbolResult<'a, T>,
+    rhs: SymbolResult<'a, V>,
     f: F,
 ) -> SymbolResult<'a, U> {
     match (lhs, rhs) {
@@ -46,6 +161,23 @@ pub fn map_op_bin<'a, T, U, F: FnOnce(T, T) -> U>(
     }
 }
 
+/// Like `map_op_bin`, for operations whose sides can fail with any `CodeError`
+pub fn map_code_bin<'a, T, V, U, F: FnOnce(T, V) -> U>(
+    lhs: Result<T, CodeError<'a>>,
+    rhs: Result<V, CodeError<'a>>,
+    f: F,
+) -> Result<U, CodeError<'a>> {
+    match (lhs, rhs) {
+        (Ok(lhs), Ok(rhs)) => Ok(f(lhs, rhs)),
+        (Err(CodeError::UndefinedSymbols(mut lhs)), Err(CodeError::UndefinedSymbols(rhs))) => {
+            lhs.extend(rhs);
+            Err(lhs.into())
+        }
+        (Err(lhs), Err(rhs)) => Err(CodeError::Multiple(vec![lhs, rhs])),
+        (Err(x), Ok(_)) | (Ok(_), Err(x)) => Err(x),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum CodeError<'a> {
     #[error("Undefined symbols: {0:?}")]
@@ -55,6 +187,142 @@ pub enum CodeError<'a> {
         err: Box<pest::error::Error<Rule>>,
         file: FileRef<'a>,
     },
+    #[error("initialised data in BSS section `{section}`")]
+    DataInBss {
+        section: &'a str,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    #[error("initialised data in OFFSET block")]
+    DataInOffset { span: Span<'a>, file: FileRef<'a> },
+    #[error("invalid alignment `{value}`, it has to be positive")]
+    Alignment {
+        value: i32,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    #[error("cannot read `{}`: {err}", path.display())]
+    Io {
+        path: PathBuf,
+        err: std::io::Error,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    #[error("range {start}..{end} is outside of `{}` ({len} bytes)", path.display())]
+    BinaryRange {
+        path: PathBuf,
+        start: i64,
+        end: i64,
+        len: usize,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    #[error("include cycle: {}", PrintIteratorSep::new(chain.iter().map(|path| path.display()), " includes "))]
+    IncludeCycle {
+        /// From the first file of the cycle until it is included again
+        chain: Vec<&'a Path>,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    #[error("symbol `{name}` is already defined")]
+    DuplicateSymbol {
+        name: &'a str,
+        span: Span<'a>,
+        file: FileRef<'a>,
+        /// Where it was defined first, `None` for symbols defined by the assembler
+        original: Option<Box<(Span<'a>, FileRef<'a>)>>,
+    },
+    /// A warning turned into an error with `-Werror`
+    #[error("{}", .0.message)]
+    Warning(Warning<'a>),
+    /// Several errors found on the same pass
+    #[error("{} errors", .0.len())]
+    Multiple(Vec<CodeError<'a>>),
+    #[error("symbol `{name}` is already defined on the command line")]
+    CommandLineRedefined {
+        name: &'a str,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    #[error("unknown option `{name}`")]
+    UnknownOption {
+        name: &'a str,
+        /// Why a warning option is not valid
+        reason: Option<String>,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    /// Raised from the source with `FAIL`, `ERROR` or `ASSERT`
+    #[error("{message}")]
+    User {
+        message: String,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    #[error("section `{name}` already defined as {kind}")]
+    SectionKind {
+        name: &'a str,
+        kind: SectionKind,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    /// Code or reserved space that goes past the last address
+    #[error("section exceeds the 32-bit address space")]
+    AddressSpace { span: Span<'a>, file: FileRef<'a> },
+    /// A `DS` or `RS` of more bytes than there are addresses
+    #[error("{count} items of {size} bytes do not fit in the 32-bit address space")]
+    ReservedSize {
+        count: u32,
+        size: u32,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    /// A `DS` or `RS` of less than no items
+    #[error("the count {count} is negative")]
+    NegativeCount {
+        count: i32,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    /// The addresses kept changing on every pass before generating the code
+    #[error("the layout of `{}` does not settle after {passes} passes", path.display())]
+    Layout { path: &'a Path, passes: usize },
+    /// The file given on the command line can not be read
+    #[error("cannot read `{}`: {err}", path.display())]
+    Input { path: &'a Path, err: std::io::Error },
+    /// An output file can not be written
+    #[error("cannot write `{}`: {err}", path.display())]
+    Output { path: &'a Path, err: std::io::Error },
+    /// A symbol whose name is not valid in the format of the symbol table
+    #[error("symbol `{name}` cannot be written to `{}`", path.display())]
+    ExportName {
+        name: &'a str,
+        reason: String,
+        path: &'a Path,
+        /// Where it was defined, `None` for symbols defined on the command line
+        definition: Option<Box<(Span<'a>, FileRef<'a>)>>,
+    },
+    /// An instruction accepted by the grammar that has no code generation
+    #[error("`{mnemonic}` is not supported yet")]
+    Unsupported {
+        mnemonic: &'a str,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    /// Something the grammar should not allow
+    #[error("internal error: {message}")]
+    Internal {
+        message: String,
+        span: Span<'a>,
+        file: FileRef<'a>,
+    },
+    /// An error found on the included file at `path`
+    #[error("{err}")]
+    Included {
+        err: Box<CodeError<'a>>,
+        path: &'a Path,
+        included_from: IncludeStack<'a>,
+    },
 }
 
 impl<'a> From<Vec<SymbolError<'a>>> for CodeError<'a> {
@@ -70,17 +338,337 @@ impl<'a> From<SymbolError<'a>> for CodeError<'a> {
 }
 
 impl<'a> CodeError<'a> {
+    /// Name that identifies the error on machine readable diagnostics, the errors that hold other
+    /// ones use the names of those instead
+    fn code(&self) -> &'static str {
+        match self {
+            Self::UndefinedSymbols(_) => "undefined-symbol",
+            Self::Parse { .. } => "syntax",
+            Self::DataInBss { .. } => "data-in-bss",
+            Self::DataInOffset { .. } => "data-in-offset",
+            Self::Alignment { .. } => "alignment",
+            Self::Io { .. } => "io",
+            Self::BinaryRange { .. } => "binary-range",
+            Self::IncludeCycle { .. } => "include-cycle",
+            Self::DuplicateSymbol { .. } => "duplicate-symbol",
+            Self::Warning(_) => "warning",
+            Self::Multiple(_) => "multiple",
+            Self::CommandLineRedefined { .. } => "command-line-redefined",
+            Self::UnknownOption { .. } => "unknown-option",
+            Self::User { .. } => "user",
+            Self::SectionKind { .. } => "section-kind",
+            Self::AddressSpace { .. } | Self::ReservedSize { .. } => "address-space",
+            Self::NegativeCount { .. } => "negative-count",
+            Self::Layout { .. } => "layout",
+            Self::Input { .. } => "input",
+            Self::Output { .. } => "output",
+            Self::ExportName { .. } => "export-name",
+            Self::Unsupported { .. } => "unsupported",
+            Self::Internal { .. } => "internal",
+            Self::Included { err, .. } => err.code(),
+        }
+    }
+
+    /// Suggests one of the defined `names` for each undefined symbol
+    pub fn suggest_symbols(&mut self, names: &[&'a str]) {
+        match self {
+            Self::UndefinedSymbols(errors) => errors.iter_mut().for_each(|err| err.suggest(names)),
+            Self::Multiple(errors) => errors.iter_mut().for_each(|err| err.suggest_symbols(names)),
+            Self::Included { err, .. } => err.suggest_symbols(names),
+            _ => {}
+        }
+    }
+
+    /// Adds the `INCLUDE`s that led to the file at `path` to the errors found on it, the ones found on
+    /// files included by it already have theirs
+    pub fn included_from(self, path: &'a Path, included_from: &IncludeStack<'a>) -> Self {
+        match self {
+            err if included_from.is_empty() => err,
+            err @ Self::Included { .. } => err,
+            Self::Multiple(errors) => Self::Multiple(
+                errors
+                    .into_iter()
+                    .map(|err| err.included_from(path, included_from))
+                    .collect(),
+            ),
+            err => Self::Included {
+                err: Box::new(err),
+                path,
+                included_from: included_from.clone(),
+            },
+        }
+    }
+
     pub fn as_display<'b, RD: RuleDisplay<'b, Rule>>(
         &'b self,
         rd: &'b RD,
     ) -> Box<dyn Iterator<Item = SpanErrorDisplay<'a>> + 'b> {
+        let error =
+            |span, file| SpanErrorDisplay::new(span, file, self.to_string()).with_code(self.code());
         match self {
             Self::UndefinedSymbols(v) => Box::new(v.iter().map(SpanErrorDisplay::<'a>::from)),
-            Self::Parse { err, file } => Box::new(std::iter::once(SpanErrorDisplay::<'a>::from((
-                err.as_ref(),
-                *file,
-                rd,
-            )))),
+            Self::Parse { err, file } => {
+                let display = SpanErrorDisplay::<'a>::from((err.as_ref(), *file, rd));
+                Box::new(std::iter::once(
+                    match suggest::for_syntax_error(err, file.str) {
+                        Some(mnemonic) => display.with_note(format!("Did you mean `{mnemonic}`?")),
+                        None => display,
+                    },
+                ))
+            }
+            Self::DataInBss { span, file, .. } => Box::new(std::iter::once(
+                error(*span, *file)
+                    .with_note("BSS sections can only reserve space, use DS instead"),
+            )),
+            Self::DataInOffset { span, file } => Box::new(std::iter::once(
+                error(*span, *file)
+                    .with_note("OFFSET blocks only define offsets, end them with ORG or a section"),
+            )),
+            Self::DuplicateSymbol {
+                name,
+                span,
+                file,
+                original,
+            } => Box::new(
+                std::iter::once(match original {
+                    Some(_) => error(*span, *file),
+                    None => error(*span, *file)
+                        .with_note(format!("`{name}` is defined by the assembler, use another name")),
+                })
+                .chain(original.as_deref().map(
+                    |&(original_span, original_file)| {
+                        SpanErrorDisplay::info(
+                            original_span,
+                            original_file,
+                            format!("`{name}` was first defined here"),
+                        )
+                        .with_code(self.code())
+                    },
+                )),
+            ),
+            Self::Warning(warning) => Box::new(std::iter::once(warning.as_display(true))),
+            Self::Multiple(errors) => Box::new(errors.iter().flat_map(|err| err.as_display(rd))),
+            // Other files, like where a duplicate symbol was first defined, may be reached otherwise
+            Self::Included {
+                err,
+                path,
+                included_from,
+            } => Box::new(err.as_display(rd).map(|display| {
+                if display.file.path == *path {
+                    display.with_included_from(included_from)
+                } else {
+                    display
+                }
+            })),
+            Self::CommandLineRedefined { span, file, .. } => Box::new(std::iter::once(
+                error(*span, *file).with_note("Use SET to change its value"),
+            )),
+            Self::UnknownOption {
+                span, file, reason, ..
+            } => {
+                let display = error(*span, *file);
+                Box::new(std::iter::once(match reason {
+                    Some(reason) => display.with_note(reason.clone()),
+                    None => display
+                        .with_note("Warnings are set with OPT W<warning> or OPT Wno-<warning>"),
+                }))
+            }
+            Self::Input { path, .. } | Self::Output { path, .. } => Box::new(std::iter::once(
+                SpanErrorDisplay::for_file(path, self.to_string()).with_code(self.code()),
+            )),
+            Self::Layout { path, .. } => Box::new(std::iter::once(
+                SpanErrorDisplay::for_file(path, self.to_string())
+                    .with_code(self.code())
+                    .with_note("The size of some code depends on addresses that it changes itself, like a branch that is promoted or not depending on where its target ends up"),
+            )),
+            Self::ExportName {
+                reason,
+                path,
+                definition,
+                ..
+            } => {
+                let display = match definition.as_deref() {
+                    Some(&(span, file)) => error(span, file),
+                    None => SpanErrorDisplay::for_file(path, self.to_string()).with_code(self.code()),
+                };
+                Box::new(std::iter::once(display.with_note(reason.clone())))
+            }
+            Self::Internal { span, file, .. } => Box::new(std::iter::once(
+                error(*span, *file)
+                    .with_note("This is a bug of the assembler, please report it with this source"),
+            )),
+            Self::Alignment { span, file, .. }
+            | Self::Unsupported { span, file, .. }
+            | Self::Io { span, file, .. }
+            | Self::BinaryRange { span, file, .. }
+            | Self::SectionKind { span, file, .. }
+            | Self::AddressSpace { span, file }
+            | Self::ReservedSize { span, file, .. }
+            | Self::NegativeCount { span, file, .. }
+            | Self::IncludeCycle { span, file, .. }
+            | Self::User { span, file, .. } => Box::new(std::iter::once(error(*span, *file))),
+        }
+    }
+}
+
+/// Category of a warning, used to enable or disable it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum WarningKind {
+    /// A value does not fit in its size and is truncated
+    Truncation,
+    /// A symbol is defined but never used, disabled by default
+    UnusedSymbol,
+    /// An alignment that is not a power of two
+    OddAlignment,
+    /// A directive without size, which is taken as `.W`, disabled by default
+    ImplicitSize,
+    /// Syntax kept for old sources, like `$-10`
+    DeprecatedSyntax,
+    /// Code after `END`
+    IgnoredCode,
+    /// Two names that are the same symbol with `--case-insensitive` or `--significant-length`
+    SymbolCollision,
+    /// A word or long access to an odd address, which is an address error on the 68000
+    OddAddress,
+    /// Raised from the source with `WARNING`
+    User,
+}
+
+impl WarningKind {
+    const fn enabled_by_default(self) -> bool {
+        !matches!(self, Self::UnusedSymbol | Self::ImplicitSize)
+    }
+
+    fn name(self) -> String {
+        self.to_possible_value().unwrap().get_name().to_owned()
+    }
+}
+
+/// Warning that can be disabled by its category
+#[derive(Debug)]
+pub struct Warning<'a> {
+    pub kind: WarningKind,
+    message: String,
+    note: Option<Cow<'static, str>>,
+    span: Span<'a>,
+    pub file: FileRef<'a>,
+}
+
+impl<'a> Warning<'a> {
+    pub const fn new(
+        kind: WarningKind,
+        span: Span<'a>,
+        file: FileRef<'a>,
+        message: String,
+    ) -> Self {
+        Self {
+            kind,
+            message,
+            note: None,
+            span,
+            file,
+        }
+    }
+
+    pub fn with_note<N: Into<Cow<'static, str>>>(mut self, note: N) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+
+    /// Display with the flag that controls it, `as_error` with `-Werror`
+    pub fn as_display(&self, as_error: bool) -> SpanErrorDisplay<'a> {
+        let name = self.kind.name();
+        let display = if as_error {
+            let message = format!("{} [-Werror={name}]", self.message);
+            SpanErrorDisplay::new(self.span, self.file, message)
+        } else {
+            let message = format!("{} [-W{name}]", self.message);
+            SpanErrorDisplay::warning(self.span, self.file, message)
+        };
+        let display = display.with_code(name);
+        match &self.note {
+            Some(note) => display.with_note(note.clone()),
+            None => display,
+        }
+    }
+}
+
+/// Option given with `-W` or `OPT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningFlag {
+    Enable(WarningKind),
+    Disable(WarningKind),
+    /// `all`, enables every warning
+    All,
+    /// `error`, warnings are errors
+    Error,
+    /// `no-error`
+    NoError,
+}
+
+impl FromStr for WarningFlag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = |name| {
+            WarningKind::from_str(name, true).map_err(|_| {
+                format!(
+                    "unknown warning `{name}`, it has to be one of: {}",
+                    PrintIteratorSep::new(
+                        WarningKind::value_variants().iter().map(|kind| kind.name()),
+                        ", "
+                    )
+                )
+            })
+        };
+        match s.to_ascii_lowercase().as_str() {
+            "all" => Ok(Self::All),
+            "error" => Ok(Self::Error),
+            "no-error" => Ok(Self::NoError),
+            _ => match s.get(..3) {
+                Some(no) if no.eq_ignore_ascii_case("no-") => kind(&s[3..]).map(Self::Disable),
+                _ => kind(s).map(Self::Enable),
+            },
+        }
+    }
+}
+
+/// Enabled warnings and whether they are errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WarningOptions {
+    enabled: HashSet<WarningKind>,
+    pub error: bool,
+}
+
+impl Default for WarningOptions {
+    fn default() -> Self {
+        Self {
+            enabled: WarningKind::value_variants()
+                .iter()
+                .copied()
+                .filter(|kind| kind.enabled_by_default())
+                .collect(),
+            error: false,
+        }
+    }
+}
+
+impl WarningOptions {
+    pub fn is_enabled(&self, kind: WarningKind) -> bool {
+        self.enabled.contains(&kind)
+    }
+
+    pub fn apply(&mut self, flag: WarningFlag) {
+        match flag {
+            WarningFlag::Enable(kind) => {
+                self.enabled.insert(kind);
+            }
+            WarningFlag::Disable(kind) => {
+                self.enabled.remove(&kind);
+            }
+            WarningFlag::All => self.enabled.extend(WarningKind::value_variants()),
+            WarningFlag::Error => self.error = true,
+            WarningFlag::NoError => self.error = false,
         }
     }
 }
@@ -89,6 +677,8 @@ impl<'a> CodeError<'a> {
 enum ErrorLocation {
     Single(usize),
     Span(usize, usize),
+    /// The file as a whole, for errors that are not about its contents
+    File,
 }
 
 impl From<InputLocation> for ErrorLocation {
@@ -101,20 +691,23 @@ impl From<InputLocation> for ErrorLocation {
 }
 
 impl ErrorLocation {
-    fn split(self, input: &str) -> (Position, Option<Position>) {
+    /// Start and end, `None` if it is the whole file
+    #[allow(mismatched_lifetime_syntaxes)]
+    fn split(self, input: &str) -> Option<(Position, Option<Position>)> {
         match self {
-            Self::Single(x) => (Position::new(input, x).unwrap(), None),
-            Self::Span(a, b) => (
-                Position::new(input, a).unwrap(),
-                Some(Position::new(input, b).unwrap()),
-            ),
+            Self::Single(x) => Some((Position::new(input, x)?, None)),
+            Self::Span(a, b) => Some((Position::new(input, a)?, Position::new(input, b))),
+            Self::File => None,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorKind {
     Error,
     Warning,
+    /// Output requested from the source with `PRINT`
+    Info,
 }
 
 impl Display for ErrorKind {
@@ -122,26 +715,156 @@ impl Display for ErrorKind {
         match self {
             Self::Error => write!(f, "[ERR]"),
             Self::Warning => write!(f, "[WARN]"),
+            Self::Info => write!(f, "[INFO]"),
         }
     }
 }
 
 pub struct SpanErrorDisplay<'a> {
     position: ErrorLocation,
-    file: FileRef<'a>,
-    kind: ErrorKind,
-    message: String,
-    note: Option<Cow<'static, str>>,
+    pub file: FileRef<'a>,
+    pub kind: ErrorKind,
+    /// Name of the error or of the warning category, for machine readable diagnostics
+    pub code: Cow<'static, str>,
+    pub message: String,
+    pub note: Option<Cow<'static, str>>,
+    /// `INCLUDE`s that led to the file, empty for the main file
+    pub included_from: IncludeStack<'a>,
+}
+
+impl<'a> SpanErrorDisplay<'a> {
+    pub fn new(span: Span<'a>, file: FileRef<'a>, message: String) -> Self {
+        Self {
+            position: ErrorLocation::Span(span.start(), span.end()),
+            file,
+            kind: ErrorKind::Error,
+            code: "error".into(),
+            message,
+            note: None,
+            included_from: Vec::new(),
+        }
+    }
+
+    pub fn warning(span: Span<'a>, file: FileRef<'a>, message: String) -> Self {
+        Self {
+            kind: ErrorKind::Warning,
+            ..Self::new(span, file, message)
+        }
+    }
+
+    pub fn info(span: Span<'a>, file: FileRef<'a>, message: String) -> Self {
+        Self {
+            kind: ErrorKind::Info,
+            ..Self::new(span, file, message)
+        }
+    }
+
+    pub fn with_note<N: Into<Cow<'static, str>>>(mut self, note: N) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+
+    /// Error about the file at `path` and not about its contents
+    pub fn for_file(path: &'a Path, message: String) -> Self {
+        Self {
+            position: ErrorLocation::File,
+            file: FileRef::new(path, ""),
+            kind: ErrorKind::Error,
+            code: "error".into(),
+            message,
+            note: None,
+            included_from: Vec::new(),
+        }
+    }
+
+    pub fn with_included_from(mut self, included_from: &IncludeStack<'a>) -> Self {
+        self.included_from.clone_from(included_from);
+        self
+    }
+
+    pub fn with_code<C: Into<Cow<'static, str>>>(mut self, code: C) -> Self {
+        self.code = code.into();
+        self
+    }
+
+    /// Line and column where it starts and where it ends, which is exclusive, `None` if it is about
+    /// the whole file
+    pub fn range(&self) -> Option<((usize, usize), (usize, usize))> {
+        let (start, end) = self.position.split(self.file.str)?;
+        let start = start.line_col();
+        Some((start, end.map_or(start, |end| end.line_col())))
+    }
+
+    fn fmt_included_from(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (path, line) in &self.included_from {
+            writeln!(f, "included from {}:{line}", path.display())?;
+        }
+        Ok(())
+    }
 }
 
 impl<'a> From<&SymbolError<'a>> for SpanErrorDisplay<'a> {
     fn from(value: &SymbolError<'a>) -> Self {
+        let symbol = value.symbol_loc.as_str();
+        let suggestion = value
+            .suggestion
+            .map(|suggestion| format!("Did you mean `{suggestion}`?").into());
+        let (message, note) = match value.kind {
+            SymbolErrorKind::Undefined => (
+                format!("symbol `{symbol}` is undefined"),
+                suggestion.or_else(|| {
+                    value.in_layout.then(|| {
+                        "The layout depends on this expression, so its symbols have to be defined before this line".into()
+                    })
+                }),
+            ),
+            SymbolErrorKind::RegisterList => (
+                format!("symbol `{symbol}` is a register list, it can not be used as a value"),
+                None,
+            ),
+            SymbolErrorKind::NotRegisterList => (
+                format!("symbol `{symbol}` is not a register list"),
+                Some("Register lists are defined with REG".into()),
+            ),
+            SymbolErrorKind::DivisionByZero => ("division by zero".to_owned(), None),
+            SymbolErrorKind::ShiftOverflow(bits) => (
+                format!("shift by {bits} bits is out of range"),
+                Some("Shifts have to be between 0 and 31 bits".into()),
+            ),
+            SymbolErrorKind::LiteralOverflow => (
+                format!("number `{symbol}` does not fit in 32 bits"),
+                None,
+            ),
+            SymbolErrorKind::UndefinedExport => (
+                format!("symbol `{symbol}` is exported but never defined"),
+                suggestion,
+            ),
+            SymbolErrorKind::OutOfRange {
+                value,
+                field,
+                min,
+                max,
+            } => (
+                format!("value {value} does not fit in {field}"),
+                Some(format!("It has to be between {min} and {max}").into()),
+            ),
+            SymbolErrorKind::BranchOutOfRange { distance, short } => (
+                format!("branch to `{symbol}` is {distance} bytes away"),
+                Some(if short {
+                    "A byte displacement reaches from -128 to 127 bytes, except 0, use `.W` for a longer branch".into()
+                } else {
+                    "A word displacement reaches from -32768 to 32767 bytes, `--promote-branches` makes it a `JMP` or `JSR`".into()
+                }),
+            ),
+        };
         Self {
             position: ErrorLocation::Span(value.symbol_loc.start(), value.symbol_loc.end()),
             file: value.file,
             kind: ErrorKind::Error,
-            message: format!("symbol `{}` is undefined", value.symbol_loc.as_str()),
-            note: Some("Maybe it is on an expression for ORG, in which case the symbol has to be defined before this line".into())
+            code: value.kind.code().into(),
+            message,
+            note,
+            included_from: Vec::new(),
         }
     }
 }
@@ -152,16 +875,6 @@ pub trait RuleDisplay<'a, R> {
     fn rule_as_display(&self, r: &'a R) -> Self::Displayable;
 }
 
-pub struct NoChange;
-
-impl<'a, R: Display + 'a> RuleDisplay<'a, R> for NoChange {
-    type Displayable = &'a R;
-
-    fn rule_as_display(&self, r: &'a R) -> Self::Displayable {
-        r
-    }
-}
-
 impl<'a, R: 'a, U: Display + 'a, F: Fn(&'a R) -> U> RuleDisplay<'a, R> for F {
     type Displayable = U;
 
@@ -170,6 +883,20 @@ impl<'a, R: 'a, U: Display + 'a, F: Fn(&'a R) -> U> RuleDisplay<'a, R> for F {
     }
 }
 
+/// Names of `rules` shown by `rd`, without repeating the rules that are shown the same way
+fn rule_names<'b, R, RD: RuleDisplay<'b, R>>(rules: &'b [R], rd: &'b RD) -> Vec<String> {
+    let mut names = Vec::new();
+    for name in rules
+        .iter()
+        .map(|rule| rd.rule_as_display(rule).to_string())
+    {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
 impl<'a, 'b, R, RD: RuleDisplay<'b, R>> From<(&'b pest::error::Error<R>, FileRef<'a>, &'b RD)>
     for SpanErrorDisplay<'a>
 {
@@ -178,41 +905,34 @@ impl<'a, 'b, R, RD: RuleDisplay<'b, R>> From<(&'b pest::error::Error<R>, FileRef
             position: error.location.clone().into(),
             file,
             kind: ErrorKind::Error,
+            code: "syntax".into(),
             message: match &error.variant {
                 pest::error::ErrorVariant::ParsingError {
                     positives,
                     negatives,
-                } => match (positives.is_empty(), negatives.is_empty()) {
-                    (true, true) => "unknown parsing error".into(),
-                    (true, false) => format!(
-                        "unexpected {}",
-                        PrintIteratorSep::new(
-                            negatives.iter().map(|x| rd.rule_as_display(x)),
-                            ", "
-                        )
-                    ),
-                    (false, true) => format!(
-                        "expected {}",
-                        PrintIteratorSep::new(
-                            positives.iter().map(|x| rd.rule_as_display(x)),
-                            ", "
-                        )
-                    ),
-                    (false, false) => format!(
-                        "unexpected {}; expected: {}",
-                        PrintIteratorSep::new(
-                            negatives.iter().map(|x| rd.rule_as_display(x)),
-                            ", "
+                } => {
+                    let positives = rule_names(positives, rd);
+                    let negatives = rule_names(negatives, rd);
+                    match (positives.is_empty(), negatives.is_empty()) {
+                        (true, true) => "unknown parsing error".into(),
+                        (true, false) => format!(
+                            "unexpected {}",
+                            PrintIteratorSep::new(negatives.iter(), ", ")
                         ),
-                        PrintIteratorSep::new(
-                            positives.iter().map(|x| rd.rule_as_display(x)),
-                            ", "
-                        )
-                    ),
-                },
+                        (false, true) => {
+                            format!("expected {}", PrintIteratorSep::new(positives.iter(), ", "))
+                        }
+                        (false, false) => format!(
+                            "unexpected {}; expected: {}",
+                            PrintIteratorSep::new(negatives.iter(), ", "),
+                            PrintIteratorSep::new(positives.iter(), ", ")
+                        ),
+                    }
+                }
                 pest::error::ErrorVariant::CustomError { message } => message.clone(),
             },
             note: None,
+            included_from: Vec::new(),
         }
     }
 }
@@ -242,7 +962,19 @@ impl<'a> Display for SpanErrorDisplay<'a> {
         const MARGIN: usize = 1;
         const TAB: &str = "    ";
 
-        let (start, end) = self.position.split(self.file.str);
+        let Some((start, end)) = self.position.split(self.file.str) else {
+            writeln!(
+                f,
+                "{} {} -> {}",
+                self.kind,
+                self.message,
+                self.file.path.display()
+            )?;
+            if let Some(note) = self.note.as_ref() {
+                writeln!(f, "note: {note}")?;
+            }
+            return self.fmt_included_from(f);
+        };
         let (sline, scol) = start.line_col();
         let end_lcol = end.as_ref().map(|x| x.line_col());
         let end_line = end_lcol.map(|(l, _)| l).unwrap_or(sline);
@@ -261,7 +993,7 @@ impl<'a> Display for SpanErrorDisplay<'a> {
             .str
             .lines()
             .enumerate()
-            .skip(sline - MARGIN - 1)
+            .skip((sline - 1).saturating_sub(MARGIN))
             .take(linen)
             .map(|(i, l)| (i + 1, l))
         {
@@ -289,6 +1021,6 @@ impl<'a> Display for SpanErrorDisplay<'a> {
         if let Some(note) = self.note.as_ref() {
             writeln!(f, "note: {note}")?;
         }
-        Ok(())
+        self.fmt_included_from(f)
     }
 }
@@ -28,3 +28,7 @@ impl<'a> FileRef<'a> {
         Self { path, str }
     }
 }
+
+/// `INCLUDE` statements that led to a file, as the path and line of each, from the one that included
+/// it to the one in the main file
+pub type IncludeStack<'a> = Vec<(&'a Path, usize)>;
@@ -4,10 +4,18 @@ use std::{collections::HashMap, fmt::Display, path::Path};
 enum CodeRef {
     Ref(usize),
     NoCodeFor(usize),
+    /// Binary data, only the first bytes are shown
+    Binary(usize),
+    /// No code, the value given to the label is shown instead
+    Value(usize, u32),
 }
 
 #[derive(Debug, Default, Clone)]
-pub struct Listing<'a>(HashMap<(&'a Path, usize), CodeRef>);
+pub struct Listing<'a> {
+    code: HashMap<(&'a Path, usize), CodeRef>,
+    /// Alignment padding emitted before the code of a line
+    padding: HashMap<(&'a Path, usize), usize>,
+}
 
 impl<'a> Listing<'a> {
     pub fn new() -> Self {
@@ -15,11 +23,24 @@ impl<'a> Listing<'a> {
     }
 
     pub fn add(&mut self, file: &'a Path, line: usize, code_idx: usize) {
-        self.0.insert((file, line), CodeRef::Ref(code_idx));
+        self.code.insert((file, line), CodeRef::Ref(code_idx));
     }
 
     pub fn add_no_code(&mut self, file: &'a Path, line: usize, addr_idx: usize) {
-        self.0.insert((file, line), CodeRef::NoCodeFor(addr_idx));
+        self.code.insert((file, line), CodeRef::NoCodeFor(addr_idx));
+    }
+
+    pub fn add_binary(&mut self, file: &'a Path, line: usize, code_idx: usize) {
+        self.code.insert((file, line), CodeRef::Binary(code_idx));
+    }
+
+    pub fn add_value(&mut self, file: &'a Path, line: usize, addr_idx: usize, value: u32) {
+        self.code
+            .insert((file, line), CodeRef::Value(addr_idx, value));
+    }
+
+    pub fn add_padding(&mut self, file: &'a Path, line: usize, code_idx: usize) {
+        self.padding.insert((file, line), code_idx);
     }
 
     pub const fn printable<'b>(
@@ -44,8 +65,26 @@ pub struct PrintableListing<'a> {
     file_str: &'a str,
 }
 
+/// Number of bytes of binary data shown on the listing
+const BINARY_SHOWN_BYTES: usize = 6;
+
 struct Spaced<T>(T);
 
+/// Bytes grouped in words
+fn hex_words(code: &[u8]) -> String {
+    code.iter()
+        .scan(0u8, |i, x| {
+            let old_i = *i;
+            *i = (*i + 1) % 2;
+            if old_i == 1 {
+                Some(format!("{x:02X} "))
+            } else {
+                Some(format!("{x:02X}"))
+            }
+        })
+        .collect::<String>()
+}
+
 impl<T: Display> Display for Spaced<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, " {} ", self.0)
@@ -60,13 +99,17 @@ impl Display for PrintableListing<'_> {
         // println!("{}", self.file.display());
         let mut pc = 0u32;
         for (line_no, line) in self.file_str.lines().enumerate().map(|(i, x)| (i + 1, x)) {
-            let code = if let Some(&idx) = self.listing.0.get(&(self.file, line_no)) {
+            if let Some(&idx) = self.listing.padding.get(&(self.file, line_no)) {
+                let (addr, code) = &self.code_object[idx];
+                writeln!(f, "{addr:08X}  {}", hex_words(code).trim_end())?;
+            }
+            let code = if let Some(&idx) = self.listing.code.get(&(self.file, line_no)) {
                 let (addr, code) = match idx {
-                    CodeRef::Ref(idx) => {
+                    CodeRef::Ref(idx) | CodeRef::Binary(idx) => {
                         let (a, b) = &self.code_object[idx];
                         (*a, b.as_slice())
                     }
-                    CodeRef::NoCodeFor(idx) => {
+                    CodeRef::NoCodeFor(idx) | CodeRef::Value(idx, _) => {
                         const EMPTY: &[u8] = &[];
                         (self.code_object[idx].0, EMPTY)
                     }
@@ -80,21 +123,18 @@ impl Display for PrintableListing<'_> {
                 None
             };
             let len = code.as_ref().map(|x| x.len()).unwrap_or(0);
+            let code = match self.listing.code.get(&(self.file, line_no)) {
+                Some(CodeRef::Binary(_)) => format!(
+                    "{} [{len} bytes]",
+                    hex_words(&code.unwrap_or_default()[..len.min(BINARY_SHOWN_BYTES)]).trim_end()
+                ),
+                Some(CodeRef::Value(_, value)) => format!("= {value:08X}"),
+                _ => hex_words(code.unwrap_or_default()),
+            };
             writeln!(
                 f,
                 "{pc:08X}  {:<30} {line_no:>5}  {}",
-                code.into_iter()
-                    .flatten()
-                    .scan(0u8, |i, x| {
-                        let old_i = *i;
-                        *i = (*i + 1) % 2;
-                        if old_i == 1 {
-                            Some(format!("{x:02X} "))
-                        } else {
-                            Some(format!("{x:02X}"))
-                        }
-                    })
-                    .collect::<String>(),
+                code,
                 line.trim_end()
             )?;
             pc += len as u32;
@@ -1,168 +1,1096 @@
 use std::{
-    borrow::Cow,
-    collections::HashMap,
-    fs::File,
-    io::Write,
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap, HashSet},
     path::{Path, PathBuf},
+    process::ExitCode,
 };
 
 use arena::FileArena;
-use args::Config;
+use args::{Config, DiagnosticsFormat, SymbolsFormat};
 use clap::Parser as ArgsParser;
-use codegen::{code_for_statement, statements, symbols::NonFailingMap, Statement};
-use error::CodeError;
-use parser::{ASMParser, Rule};
-use pest::Parser;
+use codegen::{
+    aligned_for_statement, code_for_statement, rs_for_statement,
+    section::{SectionKind, Sections},
+    statements, string_value,
+    symbols::{
+        is_internal, NameRules, NonFailingMap, References, ReferencingMap, Symbol, SymbolKind,
+        SymbolMap, SymbolNames, CURRENT_LOCATION, RS_COUNTER,
+    },
+    Statement,
+};
+use error::{
+    CodeError, SpanErrorDisplay, SymbolError, SymbolErrorKind, Warning, WarningFlag, WarningKind,
+    WarningOptions,
+};
+use file::{FileRef, IncludeStack};
+use parser::{parse_expression, ASMParser, Rule, RuleCategories};
+use pest::{iterators::Pair, Parser, Span};
 
-use crate::{args::Args, codegen::srec::SRec, listing::Listing, utils::IteratorExt};
+use crate::{
+    args::Args,
+    codegen::srec::SRec,
+    diagnostics::{JsonDiagnostics, SarifDiagnostics},
+    listing::Listing,
+    symbol_table::{include_guard, SymbolTable},
+    utils::IteratorExt,
+};
 
 mod arena;
 mod args;
 mod codegen;
+mod diagnostics;
 mod error;
 mod file;
 mod listing;
 mod parser;
+mod suggest;
+mod symbol_table;
 mod utils;
 
-// #[derive(Debug, Clone)]
+#[derive(Clone)]
 struct CurrentFile<'a> {
-    // pairs: Pairs<'a, Rule>,
-    path: Cow<'a, Path>,
-    entrypoint: u32,
+    file: FileRef<'a>,
+    /// Files including this one and their `INCLUDE` statements, starting from the main file
+    included_from: Vec<(FileRef<'a>, Span<'a>)>,
+}
+
+impl<'a> CurrentFile<'a> {
+    /// Path and line of the `INCLUDE`s that led to this file, from the last one
+    fn include_stack(&self) -> IncludeStack<'a> {
+        self.included_from
+            .iter()
+            .rev()
+            .map(|(file, span)| (file.path, span.start_pos().line_col().0))
+            .collect()
+    }
 }
 // #[derive(Debug, Clone, Copy)]
 struct GlobalData<'a> {
     arena: &'a FileArena<'a>,
     listing: Listing<'a>,
-    symbols: HashMap<&'a str, u32>,
+    symbols: HashMap<&'a str, Symbol>,
+    sections: Sections<'a>,
     code_object: Vec<(u32, Vec<u8>)>,
+    /// Start address given to `END`
+    start_addr: Option<u32>,
+    /// An `END` was found, everything after it is ignored
+    ended: bool,
+    /// Counter used by `RS` to lay out structures
+    rs: u32,
+    /// Location counter of the current `OFFSET` block, if any
+    offset: Option<u32>,
+    /// Symbols defined on the command line
+    defines: &'a [(String, u32)],
+    /// Directories searched for included files
+    include_dirs: Vec<PathBuf>,
+    /// Files that used `ONCE`, with canonical paths
+    once: HashSet<PathBuf>,
+    /// Where each symbol of the source was defined
+    definitions: HashMap<&'a str, (Span<'a>, FileRef<'a>)>,
+    /// Statement that defined each symbol of the source, kept for the last pass so `DEF` only sees the
+    /// symbols defined before its line on every pass
+    definition_order: HashMap<&'a str, usize>,
+    /// Statements assembled so far on this pass
+    statement: usize,
+    /// Duplicate symbols found on this pass, they do not stop it so all of them are reported
+    duplicates: Vec<CodeError<'a>>,
+    /// Spelling of each symbol, when names are not compared as written
+    names: SymbolNames<'a>,
+    /// Names that are the same symbol as another one with a different spelling, shown after the layout
+    name_warnings: Vec<Warning<'a>>,
+    /// Warnings enabled on the command line
+    default_warnings: &'a WarningOptions,
+    /// Warnings enabled at this point of the source, changed with `OPT`
+    warning_options: WarningOptions,
+    /// Definitions that are checked for uses after the last pass
+    unused_candidates: Vec<&'a str>,
+    /// Warnings turned into errors with `-Werror`
+    warning_errors: Vec<CodeError<'a>>,
+    /// Symbols exported with `XDEF`
+    exported: HashMap<&'a str, (Span<'a>, FileRef<'a>)>,
+    /// Line being assembled on the last pass, where the symbols used are recorded
+    location: Option<(&'a Path, usize)>,
+    /// Uses of each symbol on the last pass
+    references: RefCell<References<'a>>,
+    /// Warnings and `PRINT` output, shown before the errors
+    diagnostics: Vec<SpanErrorDisplay<'a>>,
+    /// Branches that are too far away become `JMP` or `JSR`
+    promote_branches: bool,
+    /// Symbol values of the previous pass, used to size the branches to symbols not defined yet
+    previous: HashMap<String, u32>,
+    /// `INCLUDE`s that led to the file being assembled, added to its diagnostics
+    included_from: IncludeStack<'a>,
+    /// Contents of the files of `INCBIN`, so they are read once and not on every pass
+    binaries: RefCell<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl<'a> GlobalData<'a> {
+    fn new(arena: &'a FileArena<'a>, conf: &'a Config) -> Self {
+        Self {
+            arena,
+            listing: Listing::new(),
+            symbols: HashMap::new(),
+            sections: Sections::new(),
+            code_object: Vec::new(),
+            start_addr: None,
+            ended: false,
+            rs: 0,
+            offset: None,
+            defines: &conf.defines,
+            include_dirs: conf.include_dirs.clone(),
+            once: HashSet::new(),
+            definitions: HashMap::new(),
+            definition_order: HashMap::new(),
+            statement: 0,
+            duplicates: Vec::new(),
+            names: SymbolNames::new(NameRules {
+                case_insensitive: conf.case_insensitive,
+                significant_length: conf.significant_length,
+            }),
+            name_warnings: Vec::new(),
+            default_warnings: &conf.warnings,
+            warning_options: conf.warnings.clone(),
+            unused_candidates: Vec::new(),
+            warning_errors: Vec::new(),
+            exported: HashMap::new(),
+            location: None,
+            references: RefCell::new(HashMap::new()),
+            diagnostics: Vec::new(),
+            promote_branches: conf.promote_branches,
+            previous: HashMap::new(),
+            included_from: Vec::new(),
+            binaries: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Resets the state that is rebuilt on each pass
+    fn start_pass(&mut self) {
+        self.sections.start_pass();
+        self.ended = false;
+        self.rs = 0;
+        self.statement = 0;
+        self.offset = None;
+        self.once.clear();
+        self.duplicates.clear();
+        self.exported.clear();
+        self.warning_options = self.default_warnings.clone();
+        self.references.get_mut().clear();
+    }
+
+    /// Shows `warning` if it is enabled at this point of the source
+    fn warn(&mut self, warning: Warning<'a>) {
+        if self.warning_options.is_enabled(warning.kind) {
+            self.emit_warning(warning);
+        }
+    }
+
+    /// Shows `warning`, which was already checked to be enabled
+    fn emit_warning(&mut self, warning: Warning<'a>) {
+        if self.warning_options.error {
+            let path = warning.file.path;
+            self.warning_errors
+                .push(CodeError::Warning(warning).included_from(path, &self.included_from));
+        } else {
+            self.diagnostics.push(
+                warning
+                    .as_display(false)
+                    .with_included_from(&self.included_from),
+            );
+        }
+    }
+
+    /// Symbols to evaluate expressions, recording their uses on the last pass
+    const fn referencing(&self) -> ReferencingMap<'_, 'a> {
+        ReferencingMap {
+            symbols: &self.symbols,
+            names: &self.names,
+            location: self.location,
+            references: &self.references,
+            previous: &self.previous,
+            definition_order: &self.definition_order,
+            statement: self.statement,
+        }
+    }
+
+    /// Value of each symbol, by the name given by the `NameRules`
+    fn symbol_values(&self) -> HashMap<String, u32> {
+        self.symbols
+            .iter()
+            .map(|(name, symbol)| (self.names.rules.canonical(name).into_owned(), symbol.value))
+            .collect()
+    }
+
+    /// Symbols that a mistyped name can be suggested to be, the ones of the assembler are not written by hand
+    fn suggestion_candidates(&self) -> Vec<&'a str> {
+        self.symbols
+            .keys()
+            .copied()
+            .filter(|name| !is_internal(name))
+            .collect()
+    }
+
+    /// Defines the symbols given on the command line
+    fn define_command_line(&mut self) {
+        for (name, value) in self.defines {
+            let (key, _) = self.names.insert(name);
+            self.symbols.insert(
+                key,
+                Symbol {
+                    value: *value,
+                    section: None,
+                    kind: SymbolKind::Define,
+                    size: 0,
+                },
+            );
+        }
+    }
+
+    /// Defines the symbol of `label`, which must not be already defined unless both are `SET`.
+    ///
+    /// Symbols defined on the command line can only be changed with `SET`. Other duplicates are kept on
+    /// `duplicates` and the first definition is left, like the ones of the symbols of the assembler.
+    fn define_label(
+        &mut self,
+        label: Pair<'a, Rule>,
+        symbol: Symbol,
+        file: FileRef<'a>,
+    ) -> Result<(), CodeError<'a>> {
+        let label_span = label.into_inner().next().unwrap();
+        if self.names.rules.canonical(label_span.as_str()) == self.names.rules.canonical(RS_COUNTER)
+        {
+            self.duplicates.push(
+                CodeError::DuplicateSymbol {
+                    name: label_span.as_str(),
+                    span: label_span.as_span(),
+                    file,
+                    original: None,
+                }
+                .included_from(file.path, &self.included_from),
+            );
+            return Ok(());
+        }
+        let (label, respelled) = self.names.insert(label_span.as_str());
+        if respelled
+            && self
+                .warning_options
+                .is_enabled(WarningKind::SymbolCollision)
+        {
+            self.name_warnings.push(
+                Warning::new(
+                    WarningKind::SymbolCollision,
+                    label_span.as_span(),
+                    file,
+                    format!("`{}` is the same symbol as `{label}`", label_span.as_str()),
+                )
+                .with_note(self.names.rules.to_string()),
+            );
+        }
+        let existing = self.symbols.get(label).map(|existing| existing.kind);
+        let redefinable = matches!(existing, Some(SymbolKind::Set | SymbolKind::Define))
+            && symbol.kind == SymbolKind::Set;
+        if existing.is_none() || redefinable {
+            if existing.is_none() && self.warning_options.is_enabled(WarningKind::UnusedSymbol) {
+                self.unused_candidates.push(label);
+            }
+            self.symbols.insert(label, symbol);
+            self.definitions
+                .entry(label)
+                .or_insert((label_span.as_span(), file));
+            self.definition_order.entry(label).or_insert(self.statement);
+        } else if existing == Some(SymbolKind::Define) {
+            return Err(CodeError::CommandLineRedefined {
+                name: label,
+                span: label_span.as_span(),
+                file,
+            });
+        } else {
+            self.duplicates.push(
+                CodeError::DuplicateSymbol {
+                    name: label,
+                    span: label_span.as_span(),
+                    file,
+                    original: self.definitions.get(label).copied().map(Box::new),
+                }
+                .included_from(file.path, &self.included_from),
+            );
+        }
+        Ok(())
+    }
 }
 
+/// Maximum number of passes used to place the sections before generating the code
+const MAX_LAYOUT_PASSES: usize = 8;
+
 fn run_passes<'a>(
     current_file: CurrentFile<'a>,
     global_data: &mut GlobalData<'a>,
     create_listing: bool,
-) -> Result<u32, CodeError<'a>> {
-    let file = global_data.arena.add(current_file.path).unwrap();
+) -> Result<(), CodeError<'a>> {
+    let mut settled = false;
+    for _ in 0..MAX_LAYOUT_PASSES {
+        global_data.previous = global_data.symbol_values();
+        global_data.symbols.clear();
+        global_data.definitions.clear();
+        global_data.definition_order.clear();
+        global_data.names.clear();
+        global_data.name_warnings.clear();
+        global_data.unused_candidates.clear();
+        global_data.define_command_line();
+        global_data.start_pass();
+        run_pass(current_file.clone(), global_data, true, false)?;
+        if !global_data.duplicates.is_empty() {
+            for warning in std::mem::take(&mut global_data.name_warnings) {
+                global_data.emit_warning(warning);
+            }
+            return Err(CodeError::Multiple(std::mem::take(
+                &mut global_data.duplicates,
+            )));
+        }
+        // Promoted branches change the size of the code, and with it the labels after them
+        let moved = global_data.sections.layout();
+        if !moved
+            && (!global_data.promote_branches
+                || global_data.symbol_values() == global_data.previous)
+        {
+            settled = true;
+            break;
+        }
+    }
+    // The addresses of the last dry pass are not final, so the code would be generated with wrong ones
+    if !settled {
+        return Err(CodeError::Layout {
+            path: current_file.file.path,
+            passes: MAX_LAYOUT_PASSES,
+        });
+    }
+    for warning in std::mem::take(&mut global_data.name_warnings) {
+        global_data.emit_warning(warning);
+    }
+    // The `SET` symbols are defined again on the last pass, they are candidates already
+    let unused_candidates = std::mem::take(&mut global_data.unused_candidates);
+    // println!("{symbols:#?}");
+    // `SET` symbols take again the values they have on each line
+    global_data
+        .symbols
+        .retain(|_, symbol| symbol.kind != SymbolKind::Set);
+    global_data.define_command_line();
+    global_data.start_pass();
+    run_pass(current_file, global_data, false, create_listing)?;
+    let unused = unused_candidates
+        .iter()
+        .filter(|name| {
+            !global_data.references.get_mut().contains_key(*name)
+                && !global_data.exported.contains_key(*name)
+        })
+        .map(|name| {
+            let (span, file) = global_data.definitions[name];
+            Warning::new(
+                WarningKind::UnusedSymbol,
+                span,
+                file,
+                format!("symbol `{name}` is never used"),
+            )
+        })
+        .collect::<Vec<_>>();
+    for warning in unused {
+        global_data.emit_warning(warning);
+    }
+    if !global_data.warning_errors.is_empty() {
+        return Err(CodeError::Multiple(std::mem::take(
+            &mut global_data.warning_errors,
+        )));
+    }
+    let mut undefined_exports = global_data
+        .exported
+        .iter()
+        .filter(|(name, _)| !global_data.symbols.contains_key(*name))
+        .map(|(_, &(span, file))| {
+            let err = SymbolError::with_kind(span, file, SymbolErrorKind::UndefinedExport);
+            (file.path, span.start(), err)
+        })
+        .collect::<Vec<_>>();
+    if undefined_exports.is_empty() {
+        return Ok(());
+    }
+    undefined_exports.sort_unstable_by_key(|&(path, start, _)| (path, start));
+    Err(undefined_exports
+        .into_iter()
+        .map(|(_, _, err)| err)
+        .collect::<Vec<_>>()
+        .into())
+}
+
+/// Assembles `current_file` on a pass, its errors get the `INCLUDE`s that led to it
+fn run_pass<'a>(
+    current_file: CurrentFile<'a>,
+    global_data: &mut GlobalData<'a>,
+    dry_run: bool,
+    create_listing: bool,
+) -> Result<(), CodeError<'a>> {
+    let path = current_file.file.path;
+    let including = std::mem::replace(&mut global_data.included_from, current_file.include_stack());
+    let res = run_file(current_file, global_data, dry_run, create_listing);
+    let included_from = std::mem::replace(&mut global_data.included_from, including);
+    res.map_err(|err| err.included_from(path, &included_from))
+}
+
+fn run_file<'a>(
+    current_file: CurrentFile<'a>,
+    global_data: &mut GlobalData<'a>,
+    dry_run: bool,
+    create_listing: bool,
+) -> Result<(), CodeError<'a>> {
+    let file = current_file.file;
     let pairs = ASMParser::parse(Rule::program, file.str).map_err(|err| CodeError::Parse {
         err: Box::new(err),
         file,
     })?;
-    let statements = statements(pairs);
-    let mut pc = current_file.entrypoint;
-    let mut include_end_addr = Vec::new();
-    for s in statements.clone() {
-        if s.as_rule() == Rule::include {
-            let include_str = s.into_inner().next().unwrap().as_str().trim_end();
-            let mut include_path = PathBuf::from(include_str);
-            if include_path.is_relative() {
-                include_path = file.path.parent().unwrap().join(include_path)
-            }
-            pc = run_passes(
-                CurrentFile {
-                    path: include_path.into(),
-                    entrypoint: pc,
-                },
-                global_data,
-                create_listing,
-            )?;
-            include_end_addr.push(pc);
-        } else {
-            // let span = s.as_span();
-            let Statement {
-                label,
-                start_addr,
-                code,
-            } = code_for_statement(s, pc, &NonFailingMap(&global_data.symbols), file, true)?;
-            pc = start_addr.unwrap_or(pc);
-            if let Some(label) = label {
-                let label_span = label.into_inner().next().unwrap();
-                let label = label_span.as_str();
-                if !global_data.symbols.contains_key(label) {
-                    global_data.symbols.insert(label, pc);
-                } else {
-                    let line_col = label_span.line_col();
-                    panic!(
-                        "Symbol `{label}` already defined ({}:{}:{})",
-                        file.path.display(),
-                        line_col.0,
-                        line_col.1
-                    )
-                }
+    for s in statements(pairs) {
+        // println!("{pc:08X} RULE {:?}", s.as_rule());
+        if global_data.ended {
+            let ignored = s.as_str().lines().next().unwrap_or_default().trim_end();
+            if !dry_run && !ignored.is_empty() {
+                let start = s.as_span().start();
+                global_data.warn(Warning::new(
+                    WarningKind::IgnoredCode,
+                    Span::new(file.str, start, start + ignored.len()).unwrap(),
+                    file,
+                    "code after END is ignored".into(),
+                ));
             }
-            pc += code.len() as u32;
-            // listing.insert((&file, span.start_pos().line_col().0), (start_addr, code));
+            break;
         }
-    }
-    // println!("{symbols:#?}");
-    let mut include_end_addr = include_end_addr.into_iter();
-    pc = current_file.entrypoint;
-    for s in statements {
-        // println!("{pc:08X} RULE {:?}", s.as_rule());
-        if s.as_rule() == Rule::include {
-            // println!("PC BEFORE: {pc:X}");
-            pc = include_end_addr.next().unwrap_or(pc);
-            // println!("PC: {pc:X}");
-        } else {
-            let span = s.as_span();
-            let Statement {
-                label: _,
-                start_addr,
-                code,
-            } = code_for_statement(s, pc, &global_data.symbols, file, false)?;
-            pc = start_addr.unwrap_or(pc);
-            let code_len = code.len();
-            let idx = global_data.code_object.len();
-            // println!("{pc:08X} {code:02X?}");
-            global_data.code_object.push((pc, code));
-            if create_listing {
-                for (line, last) in span
-                    .lines_span()
-                    .map(|line| {
-                        let line_start =
-                            if line.start_pos().line_col().0 == span.start_pos().line_col().0 {
-                                span.start_pos()
-                            } else {
-                                line.start_pos()
-                            };
-                        let line_end =
-                            if line.start_pos().line_col().0 == span.end_pos().line_col().0 {
-                                span.end_pos()
-                            } else {
-                                line.end_pos()
-                            };
-                        let new_span = line_start.span(&line_end);
-
-                        new_span
-                    })
-                    .filter(|line| !line.as_str().trim_end().is_empty())
-                    .with_last()
+        global_data.statement += 1;
+        global_data.location = (!dry_run).then(|| (file.path, statement_line(&s)));
+        if !dry_run {
+            for warning in deprecated_syntax(&s, file) {
+                global_data.warn(warning);
+            }
+        }
+        let pc = global_data
+            .offset
+            .unwrap_or_else(|| global_data.sections.current().pc);
+        global_data.symbols.insert(
+            CURRENT_LOCATION,
+            Symbol {
+                value: pc,
+                section: global_data
+                    .offset
+                    .is_none()
+                    .then(|| global_data.sections.current_idx()),
+                kind: SymbolKind::Label,
+                size: 0,
+            },
+        );
+        match s.as_rule() {
+            Rule::end => {
+                global_data.ended = true;
+                if let Some(expr) = s.into_inner().next() {
+                    let addr = if dry_run {
+                        parse_expression(
+                            expr.into_inner(),
+                            &NonFailingMap(&global_data.referencing()),
+                            file,
+                        )?
+                    } else {
+                        parse_expression(expr.into_inner(), &global_data.referencing(), file)?
+                    };
+                    global_data.start_addr = Some(addr as u32);
+                }
+            }
+            Rule::include => {
+                let span = s.as_span();
+                let include = s.into_inner().next().unwrap();
+                let include_str = if include.as_rule() == Rule::quoted_file {
+                    include.into_inner().next().unwrap().as_str()
+                } else {
+                    include.as_str().trim_end()
+                };
+                let include_path = resolve_path(file, include_str, &global_data.include_dirs);
+                let included = match global_data.arena.get_or_add(include_path.clone()) {
+                    Ok(included) => included,
+                    Err(err) => {
+                        return Err(CodeError::Io {
+                            path: include_path,
+                            err,
+                            span,
+                            file,
+                        })
+                    }
+                };
+                if global_data.once.contains(&canonical(included.path)) {
+                    continue;
+                }
+                let mut included_from = current_file.included_from.clone();
+                included_from.push((file, span));
+                if let Some(start) = included_from
+                    .iter()
+                    .position(|(includer, _)| canonical(includer.path) == canonical(included.path))
                 {
-                    // println!("{line:?}");
-                    if last {
-                        global_data
-                            .listing
-                            .add(file.path, line.start_pos().line_col().0, idx);
+                    return Err(CodeError::IncludeCycle {
+                        chain: included_from[start..]
+                            .iter()
+                            .map(|(includer, _)| includer.path)
+                            .chain(std::iter::once(included.path))
+                            .collect(),
+                        span,
+                        file,
+                    });
+                }
+                run_pass(
+                    CurrentFile {
+                        file: included,
+                        included_from,
+                    },
+                    global_data,
+                    dry_run,
+                    create_listing,
+                )?;
+            }
+            Rule::once => {
+                global_data.once.insert(canonical(file.path));
+            }
+            Rule::opt => {
+                for item in s.into_inner() {
+                    let name = item.as_str();
+                    let flag = match name.get(..1) {
+                        Some("W" | "w") => name[1..].parse::<WarningFlag>().map_err(Some),
+                        _ => Err(None),
+                    }
+                    .map_err(|reason| CodeError::UnknownOption {
+                        name,
+                        reason,
+                        span: item.as_span(),
+                        file,
+                    })?;
+                    global_data.warning_options.apply(flag);
+                }
+            }
+            Rule::xdef => {
+                for symbol in s.into_inner() {
+                    global_data
+                        .exported
+                        .entry(global_data.names.key(symbol.as_str()))
+                        .or_insert((symbol.as_span(), file));
+                }
+            }
+            Rule::section => {
+                let span = s.as_span();
+                let mut inner = s.into_inner();
+                let first = inner.next().unwrap();
+                let (name, kind) = if first.as_rule() == Rule::symbol {
+                    (first.as_str(), inner.next())
+                } else {
+                    (first.as_str(), Some(first))
+                };
+                let kind = kind.map(|x| SectionKind::from_str_ignore_case(x.as_str()).unwrap());
+                global_data.offset = None;
+                global_data
+                    .sections
+                    .switch(name, kind)
+                    .map_err(|kind| CodeError::SectionKind {
+                        name,
+                        kind,
+                        span,
+                        file,
+                    })?;
+            }
+            Rule::fail | Rule::user_error | Rule::user_warning | Rule::print | Rule::assert => {
+                // Only on the last pass, so they are shown once and with the final values
+                if !dry_run {
+                    let mut warnings = Vec::new();
+                    let diagnostic = diagnostic_for_statement(
+                        s,
+                        &global_data.referencing(),
+                        file,
+                        &mut warnings,
+                    )?;
+                    global_data.diagnostics.extend(diagnostic.map(|diagnostic| {
+                        diagnostic.with_included_from(&global_data.included_from)
+                    }));
+                    for warning in warnings {
+                        global_data.warn(warning);
+                    }
+                }
+            }
+            Rule::offset => {
+                let offset = match s.into_inner().next() {
+                    Some(expr) => {
+                        parse_expression(expr.into_inner(), &global_data.referencing(), file)?
+                            as u32
+                    }
+                    None => 0,
+                };
+                global_data.offset = Some(offset);
+            }
+            Rule::rsreset | Rule::rsset | Rule::rs => {
+                let span = s.as_span();
+                let (label, value, rs) =
+                    rs_for_statement(s, global_data.rs, &global_data.referencing(), file)?;
+                global_data.rs = rs;
+                let (key, _) = global_data.names.insert(RS_COUNTER);
+                global_data.symbols.insert(
+                    key,
+                    Symbol {
+                        value: rs,
+                        section: None,
+                        kind: SymbolKind::Equ,
+                        size: 0,
+                    },
+                );
+                if dry_run {
+                    if let Some(label) = label {
+                        let symbol = Symbol {
+                            value,
+                            section: None,
+                            kind: SymbolKind::Equ,
+                            size: rs - value,
+                        };
+                        global_data.define_label(label, symbol, file)?;
+                    }
+                    continue;
+                }
+                if create_listing {
+                    let idx = global_data.code_object.len();
+                    global_data.code_object.push((pc, vec![]));
+                    global_data.listing.add_value(
+                        file.path,
+                        span.start_pos().line_col().0,
+                        idx,
+                        value,
+                    );
+                }
+            }
+            rule => {
+                let span = s.as_span();
+                let mut warnings = Vec::new();
+                let Statement {
+                    label,
+                    label_value,
+                    start_addr,
+                    code,
+                    reserved,
+                } = match rule {
+                    Rule::incbin if dry_run => binary_for_statement(
+                        s,
+                        &NonFailingMap(&global_data.referencing()),
+                        &global_data.include_dirs,
+                        &global_data.binaries,
+                        file,
+                        true,
+                    )?,
+                    Rule::incbin => binary_for_statement(
+                        s,
+                        &global_data.referencing(),
+                        &global_data.include_dirs,
+                        &global_data.binaries,
+                        file,
+                        false,
+                    )?,
+                    Rule::even | Rule::align | Rule::cnop => {
+                        let addr = aligned_for_statement(
+                            s,
+                            pc,
+                            &global_data.referencing(),
+                            file,
+                            &mut warnings,
+                        )?;
+                        // The padding is the code of the directive itself, an `OFFSET` block
+                        // only moves its counter
+                        let code = if global_data.offset.is_some() {
+                            vec![]
+                        } else {
+                            global_data.sections.current().padding(pc, addr - pc)
+                        };
+                        Statement {
+                            label: None,
+                            label_value: None,
+                            start_addr: None,
+                            reserved: addr - pc - code.len() as u32,
+                            code,
+                        }
+                    }
+                    _ if dry_run => code_for_statement(
+                        s,
+                        pc,
+                        &NonFailingMap(&global_data.referencing()),
+                        file,
+                        true,
+                        global_data.promote_branches,
+                        &mut warnings,
+                    )?,
+                    _ => code_for_statement(
+                        s,
+                        pc,
+                        &global_data.referencing(),
+                        file,
+                        false,
+                        global_data.promote_branches,
+                        &mut warnings,
+                    )?,
+                };
+                if !dry_run {
+                    for warning in warnings {
+                        global_data.warn(warning);
+                    }
+                }
+                if rule == Rule::org {
+                    global_data.offset = None;
+                }
+                let addr = start_addr.unwrap_or(pc);
+                let len = (code.len() as u32)
+                    .checked_add(reserved)
+                    .ok_or(CodeError::AddressSpace { span, file })?;
+                let (padding, label_section) = if let Some(offset) = &mut global_data.offset {
+                    // Only offsets are defined, nothing is generated
+                    if !code.is_empty() {
+                        return Err(CodeError::DataInOffset { span, file });
+                    }
+                    *offset = addr
+                        .checked_add(len)
+                        .ok_or(CodeError::AddressSpace { span, file })?;
+                    (vec![], None)
+                } else {
+                    let section_idx = global_data.sections.current_idx();
+                    let section = global_data.sections.current();
+                    if section.kind == SectionKind::Bss && !code.is_empty() {
+                        return Err(CodeError::DataInBss {
+                            section: section.name,
+                            span,
+                            file,
+                        });
+                    }
+                    let padding = if rule == Rule::org {
+                        section.org(addr);
+                        vec![]
                     } else {
-                        global_data.listing.add_no_code(
+                        section.padding(pc, addr - pc)
+                    };
+                    section
+                        .advance(addr, len)
+                        .ok_or(CodeError::AddressSpace { span, file })?;
+                    (padding, Some(section_idx))
+                };
+                // `SET` symbols are also defined on the last pass, so they have the value they had at each line
+                let set = label_value.map(|symbol| symbol.kind) == Some(SymbolKind::Set);
+                if dry_run || set {
+                    if let Some(label) = label {
+                        let symbol = label_value.unwrap_or(Symbol {
+                            value: addr,
+                            section: label_section,
+                            kind: if label_section.is_some() {
+                                SymbolKind::Label
+                            } else {
+                                SymbolKind::Equ
+                            },
+                            size: len,
+                        });
+                        global_data.define_label(label, symbol, file)?;
+                    }
+                }
+                if dry_run {
+                    continue;
+                }
+                let in_offset = global_data.offset.is_some();
+                let section = global_data.sections.current();
+                if !padding.is_empty() {
+                    let idx = global_data.code_object.len();
+                    global_data.code_object.push((pc, padding));
+                    section.chunks.push(idx);
+                    if create_listing {
+                        global_data.listing.add_padding(
                             file.path,
-                            line.start_pos().line_col().0,
+                            span.start_pos().line_col().0,
                             idx,
                         );
                     }
-                    // println!("{line:?} {last} {:?} {:?}", line.start_pos().line_col(), line.end_pos().line_col());
                 }
+                let idx = global_data.code_object.len();
+                // println!("{pc:08X} {code:02X?}");
+                global_data.code_object.push((addr, code));
+                if !in_offset {
+                    section.chunks.push(idx);
+                }
+                if create_listing {
+                    for (line, last) in span
+                        .lines_span()
+                        .map(|line| {
+                            let line_start =
+                                if line.start_pos().line_col().0 == span.start_pos().line_col().0 {
+                                    span.start_pos()
+                                } else {
+                                    line.start_pos()
+                                };
+                            let line_end =
+                                if line.start_pos().line_col().0 == span.end_pos().line_col().0 {
+                                    span.end_pos()
+                                } else {
+                                    line.end_pos()
+                                };
+                            let new_span = line_start.span(&line_end);
+
+                            new_span
+                        })
+                        .filter(|line| !line.as_str().trim_end().is_empty())
+                        .with_last()
+                    {
+                        // println!("{line:?}");
+                        if last && rule == Rule::incbin {
+                            global_data.listing.add_binary(
+                                file.path,
+                                line.start_pos().line_col().0,
+                                idx,
+                            );
+                        } else if let (true, Some(symbol)) = (last, label_value) {
+                            global_data.listing.add_value(
+                                file.path,
+                                line.start_pos().line_col().0,
+                                idx,
+                                symbol.value,
+                            );
+                        } else if last {
+                            global_data
+                                .listing
+                                .add(file.path, line.start_pos().line_col().0, idx);
+                        } else {
+                            global_data.listing.add_no_code(
+                                file.path,
+                                line.start_pos().line_col().0,
+                                idx,
+                            );
+                        }
+                        // println!("{line:?} {last} {:?} {:?}", line.start_pos().line_col(), line.end_pos().line_col());
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Line where a statement starts without its label, which can be on a line of its own
+fn statement_line(s: &Pair<Rule>) -> usize {
+    s.clone()
+        .into_inner()
+        .find(|p| p.as_rule() != Rule::sol_label)
+        .map_or(s.as_span(), |p| p.as_span())
+        .start_pos()
+        .line_col()
+        .0
+}
+
+/// Path of a file referenced from `file`.
+///
+/// Relative paths are searched in its directory and then in `include_dirs`,
+/// if they are not found anywhere they are left relative to `file`.
+fn resolve_path(file: FileRef, path: &str, include_dirs: &[PathBuf]) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return path.into();
+    }
+    let local = file.path.parent().unwrap().join(path);
+    if local.exists() {
+        return local;
+    }
+    include_dirs
+        .iter()
+        .map(|dir| dir.join(path))
+        .find(|path| path.exists())
+        .unwrap_or(local)
+}
+
+/// Path used to know if two paths are the same file
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.into())
+}
+
+/// Raw bytes of an `INCBIN`, optionally limited by its offset and length.
+///
+/// On a dry run the offset and the length may use symbols that are not defined yet, so a range outside
+/// of the file is limited to it instead of being an error.
+fn binary_for_statement<'a, M: SymbolMap>(
+    p: Pair<'a, Rule>,
+    symbols: &M,
+    include_dirs: &[PathBuf],
+    binaries: &RefCell<HashMap<PathBuf, Vec<u8>>>,
+    file: FileRef<'a>,
+    dry_run: bool,
+) -> Result<Statement<'a>, CodeError<'a>> {
+    let span = p.as_span();
+    let mut inner = p.into_inner();
+    let mut label = inner.next();
+    let path = label
+        .take()
+        .and_then(|first| {
+            if first.as_rule() == Rule::sol_label {
+                label = Some(first);
+                inner.next()
+            } else {
+                Some(first)
+            }
+        })
+        .unwrap();
+    let path = resolve_path(
+        file,
+        path.into_inner().next().unwrap().as_str(),
+        include_dirs,
+    );
+    let mut range = inner
+        .map(|x| parse_expression(x.into_inner(), symbols, file).map_err(CodeError::from))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+    let mut binaries = binaries.borrow_mut();
+    let contents = match binaries.entry(path.clone()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => match std::fs::read(&path) {
+            Ok(contents) => entry.insert(contents),
+            Err(err) => {
+                return Err(CodeError::Io {
+                    path,
+                    err,
+                    span,
+                    file,
+                })
             }
-            pc += code_len as u32;
+        },
+    };
+    let len = contents.len() as i64;
+    let mut start = range.next().unwrap_or(0) as i64;
+    let mut end = range.next().map(|len| start + len as i64).unwrap_or(len);
+    if dry_run {
+        start = start.clamp(0, len);
+        end = end.clamp(start, len);
+    }
+    if start < 0 || end < start || end > len {
+        return Err(CodeError::BinaryRange {
+            path,
+            start,
+            end,
+            len: contents.len(),
+            span,
+            file,
+        });
+    }
+    Ok(Statement {
+        label,
+        label_value: None,
+        start_addr: None,
+        code: contents[start as usize..end as usize].to_vec(),
+        reserved: 0,
+    })
+}
+
+/// Warnings for old syntax used in a statement, like `$-10` instead of `-$10`
+fn deprecated_syntax<'a>(s: &Pair<'a, Rule>, file: FileRef<'a>) -> Vec<Warning<'a>> {
+    s.clone()
+        .into_inner()
+        .flatten()
+        .filter(|p| {
+            matches!(
+                p.as_rule(),
+                Rule::hex_number | Rule::bin_number | Rule::oct_number
+            )
+        })
+        .filter_map(|p| {
+            let digits = p.clone().into_inner().next().unwrap().as_str();
+            let prefix = p.as_str().strip_suffix(digits)?;
+            let positive = digits.strip_prefix('-')?;
+            Some(Warning::new(
+                WarningKind::DeprecatedSyntax,
+                p.as_span(),
+                file,
+                format!(
+                    "`{}` is deprecated, write `-{prefix}{positive}`",
+                    p.as_str()
+                ),
+            ))
+        })
+        .collect()
+}
+
+/// Text of the items of a diagnostic directive, expressions are shown with their value
+fn diagnostic_message<'a, M: SymbolMap>(
+    p: Pair<'a, Rule>,
+    symbols: &M,
+    file: FileRef<'a>,
+) -> Result<String, CodeError<'a>> {
+    let mut res = String::new();
+    for item in p.into_inner() {
+        if item.as_rule() == Rule::string {
+            res.push_str(&string_value(&item));
+        } else {
+            res.push_str(&parse_expression(item.into_inner(), symbols, file)?.to_string());
         }
     }
-    // println!("{} {pc_og:X}->{pc:X}", file.display());
-    Ok(pc)
+    Ok(res)
 }
 
-fn run(conf: &Config) {
+/// Output of `WARNING` and `PRINT`, or the error of `FAIL`, `ERROR` and a failed `ASSERT`
+fn diagnostic_for_statement<'a, M: SymbolMap>(
+    p: Pair<'a, Rule>,
+    symbols: &M,
+    file: FileRef<'a>,
+    warnings: &mut Vec<Warning<'a>>,
+) -> Result<Option<SpanErrorDisplay<'a>>, CodeError<'a>> {
+    let span = p.as_span();
+    // The span ends after the whitespace following the last item
+    let span = Span::new(
+        file.str,
+        span.start(),
+        span.start() + span.as_str().trim_end().len(),
+    )
+    .unwrap();
+    let rule = p.as_rule();
+    let mut inner = p.into_inner();
+    if rule == Rule::assert {
+        let mut args = inner.next().unwrap().into_inner();
+        let value = parse_expression(args.next().unwrap().into_inner(), symbols, file)?;
+        let message = args
+            .next()
+            .map(|message| diagnostic_message(message, symbols, file))
+            .transpose()?;
+        return match (value, message) {
+            (0, Some(message)) => Err(CodeError::User {
+                message: format!("assertion failed: {message}"),
+                span,
+                file,
+            }),
+            (0, None) => Err(CodeError::User {
+                message: "assertion failed".into(),
+                span,
+                file,
+            }),
+            _ => Ok(None),
+        };
+    }
+    let message = inner
+        .next()
+        .map(|message| diagnostic_message(message, symbols, file))
+        .transpose()?;
+    match rule {
+        Rule::user_warning => {
+            warnings.push(Warning::new(
+                WarningKind::User,
+                span,
+                file,
+                message.unwrap(),
+            ));
+            Ok(None)
+        }
+        Rule::print => Ok(Some(
+            SpanErrorDisplay::info(span, file, message.unwrap()).with_code("print"),
+        )),
+        _ => Err(CodeError::User {
+            message: message.unwrap_or_else(|| "assembly failed".into()),
+            span,
+            file,
+        }),
+    }
+}
+
+fn run(conf: &Config) -> ExitCode {
     let arena = FileArena::new();
+    let create_listing = conf.listing.is_some();
     // let (file, file_str) = arena.add(&conf.input_file).unwrap();
 
     // let file = &initial.file;
@@ -170,57 +1098,822 @@ fn run(conf: &Config) {
     // let files = vec![&*initial];
     // let successful_parse = ASMParser::parse(Rule::program, file_str);
 
-    let symbols = HashMap::<&str, u32>::new();
-    let code_object = Vec::<(u32, Vec<u8>)>::new();
-    let listing = Listing::new();
-    let create_listing = conf.listing.is_some();
-    let mut global_data = GlobalData {
-        arena: &arena,
-        listing,
-        symbols,
-        code_object,
+    let mut global_data = GlobalData::new(&arena, conf);
+    let res = arena
+        .get_or_add(conf.input_file.as_path())
+        .map_err(|err| CodeError::Input {
+            path: &conf.input_file,
+            err,
+        })
+        .map(|file| {
+            // The outputs are written even if there are errors, with the code assembled until then
+            let res = run_passes(
+                CurrentFile {
+                    file,
+                    included_from: Vec::new(),
+                },
+                &mut global_data,
+                create_listing,
+            );
+            (res, write_outputs(conf, &mut global_data))
+        });
+    let mut diagnostics = std::mem::take(&mut global_data.diagnostics);
+    let mut errors = match res {
+        Ok((res, written)) => [res.err(), written.err()],
+        Err(err) => [Some(err), None],
     };
-    if let Err(code) = run_passes(
-        CurrentFile {
-            path: conf.input_file.as_path().into(),
-            entrypoint: 0,
-        },
-        &mut global_data,
-        create_listing,
-    ) {
-        for displayable in code.as_display(&|rule| format!("{rule:?}")) {
-            println!("{displayable}");
+    let failed = errors.iter().any(Option::is_some);
+    let names = global_data.suggestion_candidates();
+    for err in errors.iter_mut().flatten() {
+        err.suggest_symbols(&names);
+        diagnostics.extend(err.as_display(&RuleCategories));
+    }
+    match conf.diagnostics_format {
+        DiagnosticsFormat::Text => {
+            for diagnostic in &diagnostics {
+                println!("{diagnostic}");
+            }
         }
+        DiagnosticsFormat::Json => print!("{}", JsonDiagnostics(&diagnostics)),
+        DiagnosticsFormat::Sarif => print!("{}", SarifDiagnostics(&diagnostics)),
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
     }
+}
+
+/// Writes the object file, and the listing and the symbol table if they were requested
+fn write_outputs<'a>(
+    conf: &'a Config,
+    global_data: &mut GlobalData<'a>,
+) -> Result<(), CodeError<'a>> {
+    let output_error = |path| move |err| CodeError::Output { path, err };
+    let table = SymbolTable::new(
+        &global_data.symbols,
+        &global_data.definitions,
+        &global_data.exported,
+        &global_data.sections,
+    );
     if let Some(listing_path) = &conf.listing {
-        let mut f = File::create(listing_path).unwrap();
-        for (file, contents) in global_data.arena {
-            f.write_all(
-                global_data
-                    .listing
-                    .printable(&global_data.code_object, file, contents)
-                    .to_string()
-                    .as_bytes(),
-            )
-            .unwrap();
+        let mut contents = String::new();
+        for (file, source) in global_data.arena {
+            contents += &global_data
+                .listing
+                .printable(&global_data.code_object, file, source)
+                .to_string();
+        }
+        contents += &table
+            .cross_reference(global_data.references.get_mut())
+            .to_string();
+        std::fs::write(listing_path, contents).map_err(output_error(listing_path))?;
+    }
+    if let Some(symbols_path) = &conf.symbols {
+        let table = if conf.exported_only {
+            table.only_exported()
+        } else {
+            table
+        };
+        let invalid = match conf.symbols_format {
+            SymbolsFormat::CHeader => table.c_header_errors(),
+            SymbolsFormat::LdScript => table.ld_script_errors(),
+            SymbolsFormat::Text | SymbolsFormat::Json => Vec::new(),
+        };
+        if !invalid.is_empty() {
+            return Err(CodeError::Multiple(
+                invalid
+                    .into_iter()
+                    .map(|(name, reason)| CodeError::ExportName {
+                        name,
+                        reason,
+                        path: symbols_path,
+                        definition: global_data.definitions.get(name).copied().map(Box::new),
+                    })
+                    .collect(),
+            ));
         }
-        f.flush().unwrap();
+        let contents = match conf.symbols_format {
+            SymbolsFormat::Text => table.text().to_string(),
+            SymbolsFormat::Json => table.json().to_string(),
+            SymbolsFormat::CHeader => table.c_header(&include_guard(symbols_path)).to_string(),
+            SymbolsFormat::LdScript => table.ld_script().to_string(),
+        };
+        std::fs::write(symbols_path, contents).map_err(output_error(symbols_path))?;
     }
     std::fs::write(
         &conf.out,
         SRec::new(
             global_data
-                .code_object
+                .sections
                 .iter()
+                .filter(|section| section.kind != SectionKind::Bss)
+                .flat_map(|section| section.chunks.iter())
+                .map(|&idx| &global_data.code_object[idx])
+                .filter(|(_, b)| !b.is_empty())
                 .map(|(a, b)| (*a, b.as_slice())),
+            global_data.start_addr.unwrap_or(0),
         )
         .to_string(),
     )
-    .unwrap();
+    .map_err(output_error(&conf.out))
 }
 
-fn main() {
+fn main() -> ExitCode {
     let conf = Args::parse().config();
-    run(&conf);
-    println!("Code generated");
+    let code = run(&conf);
+    // Only the diagnostics are written with the other formats, so they can be parsed
+    if code == ExitCode::SUCCESS && conf.diagnostics_format == DiagnosticsFormat::Text {
+        println!("Code generated");
+    }
+    code
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(args: &[&str]) -> Config {
+        Args::parse_from(["p68kasm", "test.s"].iter().chain(args)).config()
+    }
+
+    /// Assembles `source` as the file `test.s` and gives the result to `check`
+    fn assemble_with<T>(
+        source: &str,
+        conf: &Config,
+        check: impl for<'a> FnOnce(&mut GlobalData<'a>, Result<(), CodeError<'a>>) -> T,
+    ) -> T {
+        assemble_files_with(&[("test.s", source)], conf, check)
+    }
+
+    /// Assembles the first of `files`, the others can be included by it
+    fn assemble_files_with<T>(
+        files: &[(&str, &str)],
+        conf: &Config,
+        check: impl for<'a> FnOnce(&mut GlobalData<'a>, Result<(), CodeError<'a>>) -> T,
+    ) -> T {
+        let arena = FileArena::new();
+        let mut global_data = GlobalData::new(&arena, conf);
+        let files = files
+            .iter()
+            .map(|(path, source)| arena.add_source(Path::new(path), source))
+            .collect::<Vec<_>>();
+        let file = files[0];
+        let res = run_passes(
+            CurrentFile {
+                file,
+                included_from: Vec::new(),
+            },
+            &mut global_data,
+            true,
+        );
+        check(&mut global_data, res)
+    }
+
+    /// Code generated for `source` by address, which must assemble without errors
+    fn code(source: &str) -> Vec<(u32, Vec<u8>)> {
+        assemble_with(source, &config(&[]), |global_data, res| {
+            if let Err(err) = res {
+                panic!("{}", err);
+            }
+            global_data
+                .code_object
+                .iter()
+                .filter(|(_, code)| !code.is_empty())
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Value of each symbol of `source`, which must assemble without errors
+    fn symbols(source: &str, args: &[&str]) -> HashMap<String, u32> {
+        assemble_with(source, &config(args), |global_data, res| {
+            if let Err(err) = res {
+                panic!("{}", err);
+            }
+            global_data.symbol_values()
+        })
+    }
+
+    /// Symbol table of `source`, which must assemble without errors, as written by `write`
+    fn symbol_table(source: &str, write: impl Fn(SymbolTable) -> String) -> String {
+        assemble_with(source, &config(&[]), |global_data, res| {
+            if let Err(err) = res {
+                panic!("{}", err);
+            }
+            write(SymbolTable::new(
+                &global_data.symbols,
+                &global_data.definitions,
+                &global_data.exported,
+                &global_data.sections,
+            ))
+        })
+    }
+
+    /// Warnings and errors of `source`, assembled with `args`
+    fn displays(source: &str, args: &[&str]) -> Vec<(String, usize, String)> {
+        assemble_with(source, &config(args), |global_data, res| {
+            let mut diagnostics = std::mem::take(&mut global_data.diagnostics);
+            if let Err(err) = &res {
+                diagnostics.extend(err.as_display(&RuleCategories));
+            }
+            diagnostics
+                .iter()
+                .map(|diagnostic| {
+                    let line = diagnostic.range().map_or(0, |((line, _), _)| line);
+                    (
+                        diagnostic.code.to_string(),
+                        line,
+                        diagnostic.message.clone(),
+                    )
+                })
+                .collect()
+        })
+    }
+
+    /// Code and line of the warnings and errors of `source`, assembled with `args`
+    fn diagnostics(source: &str, args: &[&str]) -> Vec<(String, usize)> {
+        displays(source, args)
+            .into_iter()
+            .map(|(code, line, _)| (code, line))
+            .collect()
+    }
+
+    #[test]
+    fn test_sections() {
+        let source = "\tSECTION text,CODE\nstart\tMOVE.W value,D0\n\tSECTION vars,DATA\nvalue\tDC.W 5\n\tSECTION text\n\tRTS\n\tSECTION buf,BSS\nbuffer\tDS.B 16\n";
+        // Each section continues where it was left, and is placed after the previous one
+        assert_eq!(
+            code(source),
+            vec![
+                (0, vec![0x30, 0x39, 0x00, 0x00, 0x00, 0x08]),
+                (8, vec![0x00, 0x05]),
+                (6, vec![0x4E, 0x75])
+            ]
+        );
+        assert_eq!(symbols(source, &[])["buffer"], 0x0A);
+        assert_eq!(
+            diagnostics("\tSECTION buf,BSS\n\tDC.W 1\n", &[]),
+            vec![("data-in-bss".to_owned(), 2)]
+        );
+        assert_eq!(
+            diagnostics("\tSECTION buf,BSS\n\tSECTION buf,DATA\n", &[]),
+            vec![("section-kind".to_owned(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_alignment() {
+        let source = "\tORG $1000\n\tDC.B 1\n\tEVEN\n\tDC.B 2\n\tALIGN 4\n\tCNOP 2,8\n\tNOP\n";
+        assert_eq!(
+            code(source),
+            vec![
+                (0x1000, vec![1]),
+                (0x1001, vec![0]),
+                (0x1002, vec![2]),
+                (0x1003, vec![0]),
+                (0x1004, vec![0x4E, 0x71, 0x4E, 0x71, 0x4E, 0x71]),
+                (0x100A, vec![0x4E, 0x71])
+            ]
+        );
+        // Data sections are padded with zeros
+        assert_eq!(
+            code("\tSECTION vars,DATA\n\tDC.B 1\n\tALIGN 4\n"),
+            vec![(0, vec![1]), (1, vec![0, 0, 0])]
+        );
+        assert_eq!(
+            diagnostics("\tALIGN 0\n", &[]),
+            vec![("alignment".to_owned(), 1)]
+        );
+        assert_eq!(
+            diagnostics("\tALIGN 3\n", &[]),
+            vec![("odd-alignment".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_incbin_forward_reference() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests");
+        let source = format!(
+            "\tORG $1000\n\tINCBIN \"{dir}/incbin.bin\",OFF,LEN\n\tNOP\nOFF\tEQU 1\nLEN\tEQU 7\n"
+        );
+        assert_eq!(
+            code(&source),
+            vec![
+                (0x1000, b"BCDEFGH".to_vec()),
+                (0x1007, vec![0]),
+                (0x1008, vec![0x4E, 0x71])
+            ]
+        );
+        assert!(diagnostics(&source, &[]).is_empty());
+        assert_eq!(
+            diagnostics(&format!("\tINCBIN \"{dir}/incbin.bin\",0,11\n"), &[]),
+            vec![("binary-range".to_owned(), 1)]
+        );
+        assert_eq!(
+            diagnostics(&format!("\tINCBIN \"{dir}/missing.bin\"\n"), &[]),
+            vec![("io".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_register_list() {
+        let source = "regs\tREG D0-D2/A0\n\tMOVEM.L regs,-(A7)\n\tMOVEM.L (A7)+,regs\n";
+        assert_eq!(
+            code(source),
+            vec![
+                (0, vec![0x48, 0xE7, 0xE0, 0x80]),
+                (4, vec![0x4C, 0xDF, 0x01, 0x07])
+            ]
+        );
+        assert_eq!(
+            diagnostics("regs\tREG D0-D2/A0\n\tMOVE.L regs,D0\n", &[]),
+            vec![("register-list".to_owned(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_end() {
+        let source = "\tORG $1000\nstart\tNOP\n\tEND start\n\tDC.W $FFFF\n";
+        // Nothing after `END` is assembled
+        assert_eq!(code(source), vec![(0x1000, vec![0x4E, 0x71])]);
+        assemble_with(source, &config(&[]), |global_data, res| {
+            assert!(res.is_ok());
+            assert_eq!(global_data.start_addr, Some(0x1000));
+        });
+    }
+
+    #[test]
+    fn test_even_in_offset() {
+        let source =
+            "\tOFFSET\nfirst\tDS.B 3\n\tEVEN\nsecond\tDS.W 1\n\tORG $1000\n\tDC.W second\n";
+        assert_eq!(code(source), vec![(0x1000, vec![0, 4])]);
+    }
+
+    #[test]
+    fn test_structure_layout() {
+        let source = "\tRSRESET\nnext\tRS.L 1\nflag\tRS.B 1\ncount\tRS.W 2\nlength\tEQU __RS\n\tRSSET 16\nextra\tRS.B 1\n\tOFFSET 8\nfield\tDS.W 1\nother\tDS.B 1\n\tORG $1000\nstart\tNOP\n";
+        let symbols = symbols(source, &[]);
+        let value = |name| symbols[name];
+        assert_eq!(
+            ["next", "flag", "count", "length", "extra", "field", "other", "start"].map(value),
+            [0, 4, 6, 10, 16, 8, 10, 0x1000]
+        );
+        // Nothing is generated for the structures
+        assert_eq!(code(source), vec![(0x1000, vec![0x4E, 0x71])]);
+        assert_eq!(
+            diagnostics("\tOFFSET\n\tDC.W 1\n", &[]),
+            vec![("data-in-offset".to_owned(), 2)]
+        );
+        // The counter is a symbol of the assembler, so it is not listed and the source can not define it
+        let table = symbol_table(source, |table| table.text().to_string());
+        assert!(table.contains("length") && !table.contains("__RS"));
+        assert_eq!(
+            diagnostics("__RS\tEQU 5\n\tRS.B 1\n", &[]),
+            vec![("duplicate-symbol".to_owned(), 1)]
+        );
+        assert_eq!(
+            diagnostics("\tRS.B 1\n__rs\tEQU 5\n", &["--case-insensitive"]),
+            vec![("duplicate-symbol".to_owned(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_user_diagnostics() {
+        let message = |code: &str, line, message: &str| (code.to_owned(), line, message.to_owned());
+        assert_eq!(
+            displays(
+                "\tPRINT \"size \",4*2\n\tWARNING \"careful\"\n\tASSERT 2+2=4\n\tNOP\n",
+                &[]
+            ),
+            vec![
+                message("print", 1, "size 8"),
+                message("user", 2, "careful [-Wuser]")
+            ]
+        );
+        assert_eq!(
+            displays("\tERROR \"bad \",1\n", &[]),
+            vec![message("user", 1, "bad 1")]
+        );
+        assert_eq!(
+            displays("\tASSERT 1=2,\"no\"\n", &[]),
+            vec![message("user", 1, "assertion failed: no")]
+        );
+        assert_eq!(
+            displays("\tFAIL\n", &[]),
+            vec![message("user", 1, "assembly failed")]
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let files = [
+            ("test.s", "\tINCLUDE \"inc.s\"\n\tINCLUDE inc.s\n"),
+            ("inc.s", "\tONCE\n\tNOP\n"),
+        ];
+        assemble_files_with(&files, &config(&[]), |global_data, res| {
+            assert!(res.is_ok());
+            assert_eq!(global_data.code_object, vec![(0, vec![0x4E, 0x71])]);
+        });
+        let files = [
+            ("test.s", "\tINCLUDE \"a.s\"\n"),
+            ("a.s", "\tNOP\n\tINCLUDE \"test.s\"\n"),
+        ];
+        assemble_files_with(&files, &config(&[]), |_, res| {
+            let err = res.unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "include cycle: test.s includes a.s includes test.s"
+            );
+        });
+    }
+
+    #[test]
+    fn test_command_line_define() {
+        let source = "\tDS.B SIZE\nafter\tNOP\n";
+        assert_eq!(symbols(source, &["-DSIZE=$10"])["after"], 0x10);
+        assert_eq!(symbols("\tNOP\n", &["-DDEBUG"])["DEBUG"], 1);
+        assert_eq!(
+            diagnostics("SIZE\tEQU 1\n", &["-DSIZE=$10"]),
+            vec![("command-line-redefined".to_owned(), 1)]
+        );
+        assert_eq!(symbols("SIZE\tSET 1\n", &["-DSIZE=$10"])["SIZE"], 1);
+    }
+
+    #[test]
+    fn test_symbol_functions() {
+        let source =
+            "buf\tDS.W 4\nsize\tEQU SIZEOF(buf)\nhas\tEQU DEF(buf)\nnot\tEQU DEF(nothing)\n";
+        let values = symbols(source, &[]);
+        assert_eq!(values["size"], 8);
+        assert_eq!(values["has"], -1i32 as u32);
+        assert_eq!(values["not"], 0);
+        // A symbol defined further down is not defined yet on the last pass either
+        let forward = "\tDS.B DEF(b)&4\nb\tNOP\n\tDC.W b\nlater\tEQU DEF(c)\nc\tNOP\n";
+        assert_eq!(
+            code(forward),
+            vec![
+                (0, vec![0x4E, 0x71]),
+                (2, vec![0, 0]),
+                (4, vec![0x4E, 0x71])
+            ]
+        );
+        assert_eq!(symbols(forward, &[])["later"], 0);
+    }
+
+    #[test]
+    fn test_current_location_or_comment() {
+        assert_eq!(
+            code("\tORG $1000\n\tDC.L *\n\tDC.L *+2\n"),
+            vec![(0x1000, vec![0, 0, 0x10, 0]), (0x1004, vec![0, 0, 0x10, 6])]
+        );
+        // Nothing can follow an instruction without operands, so a `*` after it is a comment
+        let nop = vec![0x4E, 0x71];
+        assert_eq!(
+            code("\tNOP *\n\tNOP\t* wait\n\tMOVE.W D0,D1 * copy\n"),
+            vec![(0, nop.clone()), (2, nop), (4, vec![0x32, 0x00])]
+        );
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let error = |code: &str| vec![(code.to_owned(), 2)];
+        assert_eq!(
+            diagnostics("\tNOP\nx\tEQU 1/(2-2)\n", &[]),
+            error("division-by-zero")
+        );
+        assert_eq!(
+            diagnostics("\tNOP\nx\tEQU 1<<32\n", &[]),
+            error("shift-overflow")
+        );
+        assert_eq!(
+            diagnostics("\tNOP\nx\tEQU $100000000\n", &[]),
+            error("literal-overflow")
+        );
+        assert_eq!(symbols("x\tEQU $FFFFFFFF+2\n", &[])["x"], 1);
+    }
+
+    #[test]
+    fn test_number_literals() {
+        assert_eq!(
+            code("\tDC.B 'A',0x1F,1Fh,%1010_0101,0b11,17q\n"),
+            vec![(0, vec![0x41, 0x1F, 0x1F, 0xA5, 0x03, 0x0F])]
+        );
+        // Symbols that look like suffixed numbers are still symbols
+        assert_eq!(
+            code("ah\tEQU 2\n\tDC.B ah\n\tMOVE.B D1,D0\n"),
+            vec![(0, vec![2]), (1, vec![0]), (2, vec![0x10, 0x01])]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_symbols() {
+        let duplicate = |line| ("duplicate-symbol".to_owned(), line);
+        // Each duplicate with where the symbol was first defined
+        assert_eq!(
+            diagnostics("a\tNOP\na\tNOP\nb\tNOP\nb\tEQU 1\n", &[]),
+            vec![duplicate(2), duplicate(1), duplicate(4), duplicate(3)]
+        );
+        assert!(diagnostics("x\tSET 1\nx\tSET 2\n", &[]).is_empty());
+    }
+
+    #[test]
+    fn test_symbol_table() {
+        let source = "\tXDEF start\nstart\tNOP\nvalue\tEQU 5\n";
+        let json = symbol_table(source, |table| table.json().to_string());
+        assert!(json.contains(r#"{"name": "start", "value": 0, "kind": "label", "section": "CODE", "exported": true, "file": "test.s", "line": 2}"#));
+        assert!(json.contains(r#"{"name": "value", "value": 5, "kind": "equ", "section": null, "exported": false, "file": "test.s", "line": 3}"#));
+        let exported = symbol_table(source, |table| table.only_exported().json().to_string());
+        assert!(exported.contains(r#""by_value": ["start"]"#));
+    }
+
+    #[test]
+    fn test_reference_lines() {
+        let source =
+            "\tORG $1000\nstart\n\tBRA done\nvalue\tEQU 2\n\tMOVE.W #value,D0\ndone\n\tJMP start\n";
+        assemble_with(source, &config(&[]), |global_data, res| {
+            assert!(res.is_ok());
+            let references = global_data.references.get_mut();
+            let lines = |name| {
+                references[name]
+                    .iter()
+                    .map(|&(_, line)| line)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(lines("done"), vec![3]);
+            assert_eq!(lines("value"), vec![5]);
+            assert_eq!(lines("start"), vec![7]);
+        });
+    }
+
+    #[test]
+    fn test_symbol_export() {
+        let source = "\tXDEF start\nstart\tNOP\nvalue\tEQU 5\n";
+        let guard = include_guard(Path::new("out/symbols.h"));
+        assert_eq!(guard, "SYMBOLS_H");
+        assert_eq!(
+            symbol_table(source, |table| table.c_header(&guard).to_string()),
+            "/* Generated by p68kasm, do not edit */\n#ifndef SYMBOLS_H\n#define SYMBOLS_H\n\nextern void start(void);\n#define start_ADDR 0x00000000UL\n#define value 0x00000005UL\n\n#endif\n"
+        );
+        assert_eq!(
+            symbol_table(source, |table| table.ld_script().to_string()),
+            "/* Generated by p68kasm, do not edit */\nstart = 0x00000000;\nvalue = 0x00000005;\n"
+        );
+        // Register lists are not written, and neither are the symbols of the assembler
+        let source = "regs\tREG D0/A1\n\tRSRESET\nfield\tRS.W 1\n";
+        assert_eq!(
+            symbol_table(source, |table| table.ld_script().to_string()),
+            "/* Generated by p68kasm, do not edit */\nfield = 0x00000000;\n"
+        );
+        assert!(!symbol_table(source, |table| table.c_header("H").to_string()).contains("regs"));
+        let invalid = |source, ld_script| {
+            symbol_table(source, |table| {
+                let errors = if ld_script {
+                    table.ld_script_errors()
+                } else {
+                    table.c_header_errors()
+                };
+                errors
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+        };
+        assert_eq!(
+            invalid(
+                "my.sym\tEQU 1\nmy_sym\tEQU 2\nstart\tNOP\nstart_ADDR\tEQU 3\nint\tEQU 4\n",
+                false
+            ),
+            "int my_sym start_ADDR"
+        );
+        assert_eq!(
+            invalid("my$sym\tEQU 1\nENTRY\tEQU 2\n.ok\tEQU 3\n", true),
+            "ENTRY my$sym"
+        );
+    }
+
+    #[test]
+    fn test_symbol_names() {
+        let source = "Start\tNOP\n\tBRA start\n";
+        assert_eq!(
+            diagnostics(source, &[]),
+            vec![("undefined-symbol".to_owned(), 2)]
+        );
+        assert!(diagnostics(source, &["--case-insensitive"]).is_empty());
+        let source = "label1\tNOP\n\tBRA label2\n";
+        assert!(diagnostics(source, &["--significant-length", "5"]).is_empty());
+        assert_eq!(
+            diagnostics("LOOP\tNOP\nloop\tEQU 1\n", &["--case-insensitive"]),
+            vec![
+                ("symbol-collision".to_owned(), 2),
+                ("duplicate-symbol".to_owned(), 2),
+                ("duplicate-symbol".to_owned(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_warning_flags() {
+        let source = "\tALIGN 3\n";
+        assert_eq!(
+            displays(source, &["-Werror"]),
+            vec![(
+                "odd-alignment".to_owned(),
+                1,
+                "alignment 3 is not a power of two [-Werror=odd-alignment]".to_owned()
+            )]
+        );
+        assert!(diagnostics(source, &["-Wno-odd-alignment"]).is_empty());
+        assert!(diagnostics("\tOPT Wno-odd-alignment\n\tALIGN 3\n", &[]).is_empty());
+        assert_eq!(
+            diagnostics("\tOPT Wbogus\n", &[]),
+            vec![("unknown-option".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_unused_set_warned_once() {
+        let source = "flag\tSET 1\n\tNOP\n";
+        assert_eq!(
+            diagnostics(source, &["-Wunused-symbol"]),
+            vec![("unused-symbol".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_immediate_truncated() {
+        let source = "\tORG $1000\n\tMOVE.B #300,D0\n\tCMPI.B #300,D0\n\tANDI #$12345,SR\n";
+        assert_eq!(
+            code(source),
+            vec![
+                (0x1000, vec![0x10, 0x3C, 0x00, 0x2C]),
+                (0x1004, vec![0x0C, 0x00, 0x00, 0x2C]),
+                (0x1008, vec![0x02, 0x7C, 0x23, 0x45])
+            ]
+        );
+        let truncation = |line| ("truncation".to_owned(), line);
+        assert_eq!(
+            diagnostics(source, &[]),
+            vec![truncation(2), truncation(3), truncation(4)]
+        );
+    }
+
+    #[test]
+    fn test_field_range() {
+        let out_of_range = vec![("out-of-range".to_owned(), 1)];
+        for source in [
+            "\tLINK A6,#70000\n",
+            "\tTRAP #17\n",
+            "\tBKPT #8\n",
+            "\tMOVE.W 40000(A0),D0\n",
+            "\tMOVE.W ($9000).W,D0\n",
+        ] {
+            assert_eq!(diagnostics(source, &[]), out_of_range, "{source}");
+        }
+        assert_eq!(
+            code("\tLINK A6,#-32768\n\tTRAP #15\n\tBKPT #7\n\tMOVE.W -32768(A0),D0\n\tMOVE.W ($FFFF8000).W,D0\n"),
+            vec![
+                (0, vec![0x4E, 0x56, 0x80, 0x00]),
+                (4, vec![0x4E, 0x4F]),
+                (6, vec![0x48, 0x4F]),
+                (8, vec![0x30, 0x28, 0x80, 0x00]),
+                (12, vec![0x30, 0x38, 0x80, 0x00])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undefined_layout_note() {
+        let notes = |source| {
+            assemble_with(source, &config(&[]), |_, res| {
+                res.unwrap_err()
+                    .as_display(&RuleCategories)
+                    .map(|diagnostic| diagnostic.note.is_some())
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(notes("\tORG later\nlater\tNOP\n"), vec![true]);
+        assert_eq!(notes("\tBRA nowhere\n"), vec![false]);
+    }
+
+    #[test]
+    fn test_branch_range() {
+        assert_eq!(
+            diagnostics("\tBRA.B far\n\tDS.B 200\nfar\tNOP\n", &[]),
+            vec![("branch-out-of-range".to_owned(), 1)]
+        );
+        let source = "\tBRA far\n\tDS.B $10000\nfar\tNOP\n";
+        assert_eq!(
+            diagnostics(source, &[]),
+            vec![("branch-out-of-range".to_owned(), 1)]
+        );
+        assemble_with(
+            source,
+            &config(&["--promote-branches"]),
+            |global_data, res| {
+                assert!(res.is_ok());
+                assert_eq!(
+                    global_data.code_object[0],
+                    (0, vec![0x4E, 0xF9, 0x00, 0x01, 0x00, 0x06])
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn test_odd_address() {
+        let source =
+            "ODD\tEQU $1001\n\tMOVE.W ODD,D0\n\tMOVE.B ODD,D0\n\tPEA ODD\n\tJMP ODD\n\tJSR ODD\n";
+        assert_eq!(
+            diagnostics(source, &[]),
+            vec![("odd-address".to_owned(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_address_space() {
+        let error = |line| vec![("address-space".to_owned(), line)];
+        assert_eq!(diagnostics("\tNOP\n\tDS.L $40000000\n", &[]), error(2));
+        assert_eq!(diagnostics("\tRS.L 2\n\tRS.L $3FFFFFFF\n", &[]), error(2));
+        assert_eq!(diagnostics("\tORG $FFFFFFFE\n\tDC.L 1\n", &[]), error(2));
+        assert_eq!(
+            diagnostics("\tORG $80000000\n\tDS.B $7FFFFFFF\n\tNOP\n", &[]),
+            error(3)
+        );
+        assert_eq!(diagnostics("\tORG $FFFFFFFF\n\tEVEN\n", &[]), error(2));
+        // A negative count is not taken as a huge unsigned one
+        let negative = |line| vec![("negative-count".to_owned(), line)];
+        assert_eq!(
+            diagnostics("\tDS.B -5\n\tSECTION d,DATA\n", &[]),
+            negative(1)
+        );
+        assert_eq!(diagnostics("\tRSRESET\nfield\tRS.W -1\n", &[]), negative(2));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert_eq!(
+            diagnostics("\tNOP\n\tLEA (A0),A1\n", &[]),
+            vec![("unsupported".to_owned(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_include_chain() {
+        let files = [
+            ("test.s", "\tNOP\n\tINCLUDE \"a.s\"\n"),
+            ("a.s", "\tINCLUDE \"b.s\"\n"),
+            ("b.s", "\tALIGN 3\n\tBRA nowhere\n"),
+        ];
+        assemble_files_with(&files, &config(&[]), |global_data, res| {
+            let mut diagnostics = std::mem::take(&mut global_data.diagnostics);
+            diagnostics.extend(res.unwrap_err().as_display(&RuleCategories));
+            let chains = diagnostics
+                .iter()
+                .map(|diagnostic| {
+                    let chain = diagnostic
+                        .included_from
+                        .iter()
+                        .map(|&(path, line)| (path.to_str().unwrap(), line));
+                    (
+                        diagnostic.file.path.to_str().unwrap(),
+                        chain.collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            // Warnings and errors, the innermost include first
+            let chain = vec![("a.s", 1), ("test.s", 2)];
+            assert_eq!(chains, vec![("b.s", chain.clone()), ("b.s", chain)]);
+        });
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let note = |source| {
+            assemble_with(source, &config(&[]), |global_data, res| {
+                let mut err = res.unwrap_err();
+                err.suggest_symbols(&global_data.suggestion_candidates());
+                let notes = err
+                    .as_display(&RuleCategories)
+                    .map(|diagnostic| diagnostic.note.map(|note| note.into_owned()))
+                    .collect::<Vec<_>>();
+                notes
+            })
+        };
+        assert_eq!(
+            note("start\tNOP\n\tBRA strat\n"),
+            vec![Some("Did you mean `start`?".to_owned())]
+        );
+        // `*` and `__RS` are defined by the assembler, so they are not suggested
+        assert_eq!(
+            note("x\tEQU 1\n\tDC.W y\n"),
+            vec![Some("Did you mean `x`?".to_owned())]
+        );
+        assert_eq!(note("\tRS.B 1\n\tDC.W __R\n"), vec![None]);
+        assert_eq!(
+            note("\tmovee.w D0,D1\n"),
+            vec![Some("Did you mean `move.w`?".to_owned())]
+        );
+    }
 }
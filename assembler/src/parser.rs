@@ -1,13 +1,19 @@
+use std::{borrow::Cow, collections::HashMap, path::Path};
+
 use once_cell::sync::Lazy;
 use pest::{
-    iterators::Pairs,
+    iterators::{Pair, Pairs},
     pratt_parser::{Assoc, Op, PrattParser},
+    Parser,
 };
 use pest_derive::Parser;
 
 use crate::{
-    codegen::symbols::SymbolMap,
-    error::{map_op_bin, SymbolError},
+    codegen::{
+        string_value,
+        symbols::{Symbol, SymbolMap, CURRENT_LOCATION},
+    },
+    error::{map_op_bin, RuleDisplay, SymbolError, SymbolErrorKind},
     file::FileRef,
 };
 
@@ -15,8 +21,142 @@ use crate::{
 #[grammar = "asm2.pest"]
 pub struct ASMParser;
 
+/// Rules that start an instruction or a directive, which are expected at the start of a statement
+pub const fn is_mnemonic(rule: &Rule) -> bool {
+    matches!(
+        rule,
+        Rule::LEA
+            | Rule::LINK
+            | Rule::MOVE_mnemonic
+            | Rule::MOVEA_mnemonic
+            | Rule::MOVEM_mnemonic
+            | Rule::PEA
+            | Rule::UNLK
+            | Rule::ADD_mnemonic
+            | Rule::ADDA_mnemonic
+            | Rule::ADDI_mnemonic
+            | Rule::CLR_mnemonic
+            | Rule::CMP_mnemonic
+            | Rule::CMPA_mnemonic
+            | Rule::CMPI_mnemonic
+            | Rule::SUB_mnemonic
+            | Rule::SUBA_mnemonic
+            | Rule::SUBI_mnemonic
+            | Rule::Bcc_mnemonic
+            | Rule::BRA_mnemonic
+            | Rule::BSR_mnemonic
+            | Rule::JMP
+            | Rule::JSR
+            | Rule::NOP
+            | Rule::RTS
+            | Rule::ANDI_to_SR
+            | Rule::MOVE_to_SR
+            | Rule::MOVE_to_USP
+            | Rule::MOVE_from_USP
+            | Rule::RTE
+            | Rule::BKPT
+            | Rule::TRAP
+            | Rule::dc_mnemonic
+            | Rule::ds_mnemonic
+            | Rule::rs_mnemonic
+            | Rule::statement
+            | Rule::instruction
+    )
+}
+
+/// Shows the rules of syntax errors as the part of the source they are, so the dozens of
+/// instructions that can start a statement are a single "mnemonic"
+pub struct RuleCategories;
+
+impl<'a> RuleDisplay<'a, Rule> for RuleCategories {
+    type Displayable = Cow<'static, str>;
+
+    fn rule_as_display(&self, r: &'a Rule) -> Self::Displayable {
+        match r {
+            rule if is_mnemonic(rule) => "mnemonic".into(),
+            Rule::Dn | Rule::An | Rule::reg_no | Rule::reg_range => "register".into(),
+            Rule::reglist | Rule::reglist_symbol => "register list".into(),
+            Rule::address_indirect
+            | Rule::address_indirect_postinc
+            | Rule::address_indirect_predecr
+            | Rule::address_indirect_disp
+            | Rule::absolute_short
+            | Rule::absolute_long
+            | Rule::immediate_data => "addressing mode".into(),
+            Rule::int_size | Rule::word_size | Rule::small_size => "size".into(),
+            Rule::cc | Rule::bcc_cc => "condition".into(),
+            Rule::expression
+            | Rule::atom
+            | Rule::d16
+            | Rule::dec_number
+            | Rule::hex_number
+            | Rule::bin_number
+            | Rule::oct_number
+            | Rule::dec_digits
+            | Rule::hex_digits
+            | Rule::bin_digits
+            | Rule::oct_digits
+            | Rule::current_location
+            | Rule::char_constant
+            | Rule::def_function
+            | Rule::hi_function
+            | Rule::lo_function
+            | Rule::strlen_function
+            | Rule::sizeof_function => "expression".into(),
+            Rule::add_op
+            | Rule::subtract
+            | Rule::multiply
+            | Rule::divide
+            | Rule::modulo
+            | Rule::and_op
+            | Rule::or_op
+            | Rule::rshift
+            | Rule::lshift
+            | Rule::equal
+            | Rule::not_equal
+            | Rule::less
+            | Rule::less_equal
+            | Rule::greater
+            | Rule::greater_equal
+            | Rule::logical_and
+            | Rule::logical_or
+            | Rule::neg_op
+            | Rule::not_op => "operator".into(),
+            Rule::symbol => "symbol".into(),
+            Rule::sol_label => "label".into(),
+            Rule::string | Rule::dc_string | Rule::escape_seq => "string".into(),
+            Rule::quoted_file | Rule::file_name | Rule::include_file => "file name".into(),
+            Rule::section_kind => "section kind".into(),
+            Rule::opt_item => "option".into(),
+            Rule::EOL => "end of line".into(),
+            Rule::EOI => "end of file".into(),
+            rule => format!("{rule:?}").to_lowercase().replace('_', " ").into(),
+        }
+    }
+}
+
+/// Value of true on relational and logical operators
+const TRUE: i64 = -1;
+
+const fn truth(b: bool) -> i64 {
+    if b {
+        TRUE
+    } else {
+        0
+    }
+}
+
 static PRATT_PARSER: Lazy<PrattParser<Rule>> = Lazy::new(|| {
+    // Motorola precedence, from the lowest: logical, relational, additive, multiplicative, bitwise and shifts
     PrattParser::new()
+        .op(Op::infix(Rule::logical_or, Assoc::Left))
+        .op(Op::infix(Rule::logical_and, Assoc::Left))
+        .op(Op::infix(Rule::equal, Assoc::Left)
+            | Op::infix(Rule::not_equal, Assoc::Left)
+            | Op::infix(Rule::less, Assoc::Left)
+            | Op::infix(Rule::less_equal, Assoc::Left)
+            | Op::infix(Rule::greater, Assoc::Left)
+            | Op::infix(Rule::greater_equal, Assoc::Left))
         .op(Op::infix(Rule::add_op, Assoc::Left) | Op::infix(Rule::subtract, Assoc::Left))
         .op(Op::infix(Rule::multiply, Assoc::Left)
             | Op::infix(Rule::divide, Assoc::Left)
@@ -26,42 +166,120 @@ static PRATT_PARSER: Lazy<PrattParser<Rule>> = Lazy::new(|| {
         .op(Op::prefix(Rule::neg_op) | Op::prefix(Rule::not_op))
 });
 
+/// Value of an expression, wrapped to 32 bits.
+///
+/// Numbers and symbols are signed 32 bit values, but the operations are done with 64 bits, so only the final result wraps.
 pub fn parse_expression<'b, M: SymbolMap>(
     pairs: Pairs<'b, Rule>,
     symbols: &M,
     current_file: FileRef<'b>,
 ) -> Result<i32, Vec<SymbolError<'b>>> {
+    eval_expression(pairs, symbols, current_file).map(|x| x as i32)
+}
+
+/// Value of `s` written like an expression of the source without symbols, `None` if it is not one
+pub fn parse_constant(s: &str) -> Option<i32> {
+    let expression = ASMParser::parse(Rule::expression, s).ok()?.next()?;
+    // There is no current location outside of the source
+    let location = || {
+        expression
+            .clone()
+            .into_inner()
+            .flatten()
+            .any(|p| p.as_rule() == Rule::current_location)
+    };
+    if expression.as_str() != s || location() {
+        return None;
+    }
+    let symbols = HashMap::<&str, Symbol>::new();
+    parse_expression(
+        Pairs::single(expression),
+        &symbols,
+        FileRef::new(Path::new("<command line>"), s),
+    )
+    .ok()
+}
+
+/// Value of a number literal, which has to fit in 32 bits (signed or unsigned) and is taken as signed
+fn parse_number<'b>(
+    p: &Pair<'b, Rule>,
+    radix: u32,
+    current_file: FileRef<'b>,
+) -> Result<i64, Vec<SymbolError<'b>>> {
+    let digits = p
+        .clone()
+        .into_inner()
+        .next()
+        .unwrap()
+        .as_str()
+        .replace('_', "");
+    i64::from_str_radix(&digits, radix)
+        .ok()
+        .filter(|x| (i32::MIN as i64..=u32::MAX as i64).contains(x))
+        .map(|x| x as i32 as i64)
+        .ok_or_else(|| {
+            vec![SymbolError::with_kind(
+                p.as_span(),
+                current_file,
+                SymbolErrorKind::LiteralOverflow,
+            )]
+        })
+}
+
+fn eval_expression<'b, M: SymbolMap>(
+    pairs: Pairs<'b, Rule>,
+    symbols: &M,
+    current_file: FileRef<'b>,
+) -> Result<i64, Vec<SymbolError<'b>>> {
     PRATT_PARSER
         .map_primary(|primary| match primary.as_rule() {
-            Rule::expression => parse_expression(primary.into_inner(), symbols, current_file),
+            Rule::expression => eval_expression(primary.into_inner(), symbols, current_file),
             Rule::atom => {
                 let inner = primary.into_inner().next().unwrap();
                 match inner.as_rule() {
-                    Rule::expression => parse_expression(inner.into_inner(), symbols, current_file),
-                    Rule::dec_number => Ok(inner
-                        .as_str()
-                        .parse()
-                        .or_else(|_| inner.as_str().parse::<u32>().map(|x| x as i32))
-                        .unwrap()),
-                    Rule::hex_number => Ok(i32::from_str_radix(&inner.as_str()[1..], 16)
-                        .or_else(|_| {
-                            u32::from_str_radix(&inner.as_str()[1..], 16).map(|x| x as i32)
-                        })
-                        .unwrap()),
-                    Rule::oct_number => Ok(i32::from_str_radix(&inner.as_str()[1..], 8)
-                        .or_else(|_| u32::from_str_radix(&inner.as_str()[1..], 8).map(|x| x as i32))
-                        .unwrap()),
-                    Rule::bin_number => Ok(i32::from_str_radix(&inner.as_str()[1..], 2)
-                        .or_else(|_| u32::from_str_radix(&inner.as_str()[1..], 2).map(|x| x as i32))
-                        .unwrap()),
+                    Rule::expression => eval_expression(inner.into_inner(), symbols, current_file),
+                    Rule::dec_number => parse_number(&inner, 10, current_file),
+                    Rule::hex_number => parse_number(&inner, 16, current_file),
+                    Rule::oct_number => parse_number(&inner, 8, current_file),
+                    Rule::bin_number => parse_number(&inner, 2, current_file),
+                    Rule::current_location => Ok(symbols.get(CURRENT_LOCATION).unwrap() as i64),
+                    Rule::char_constant => Ok(string_value(&inner)
+                        .bytes()
+                        .fold(0u32, |acc, b| acc << 8 | b as u32)
+                        as i64),
+                    Rule::def_function => {
+                        let name = inner.into_inner().next().unwrap().as_str();
+                        Ok(truth(symbols.is_defined(name)))
+                    }
+                    Rule::hi_function => eval_expression(inner.into_inner(), symbols, current_file)
+                        .map(|x| (x >> 16) & 0xFFFF),
+                    Rule::lo_function => eval_expression(inner.into_inner(), symbols, current_file)
+                        .map(|x| x & 0xFFFF),
+                    Rule::strlen_function => {
+                        Ok(string_value(&inner.into_inner().next().unwrap()).len() as i64)
+                    }
+                    Rule::sizeof_function => {
+                        let symbol = inner.into_inner().next().unwrap();
+                        symbols
+                            .get_size(symbol.as_str())
+                            .map(|size| size as i64)
+                            .ok_or_else(|| vec![SymbolError::new(symbol.as_span(), current_file)])
+                    }
                     Rule::symbol => {
                         symbols.get(inner.as_str()).map_or_else(
                             || {
                                 // let span_start = inner.as_span().start_pos().line_col();
+                                if symbols.get_register_list(inner.as_str()).is_some() {
+                                    return Err(vec![SymbolError::with_kind(
+                                        inner.as_span(),
+                                        current_file,
+                                        SymbolErrorKind::RegisterList,
+                                    )]);
+                                }
                                 Err(vec![SymbolError::new(inner.as_span(), current_file)])
                                 // panic!("Symbol `{}` undefined ({}:{}:{}) (Maybe it is on an expression for ORG, in which case the symbol has to be defined before this line)", inner.as_str(), current_file, span_start.0, span_start.1)
                             },
-                            |val| Ok(val as i32),
+                            |val| Ok(val as i32 as i64),
                         )
                     }
                     _ => unreachable!(),
@@ -70,21 +288,42 @@ pub fn parse_expression<'b, M: SymbolMap>(
             _ => unreachable!(),
         })
         .map_prefix(|op, data| match op.as_rule() {
-            Rule::neg_op => data.map(|x| -x),
+            Rule::neg_op => data.map(|x| x.wrapping_neg()),
             Rule::not_op => data.map(|x| !x),
             _ => unreachable!(),
         })
-        .map_infix(|lhs, op, rhs| match op.as_rule() {
-            Rule::add_op => map_op_bin(lhs, rhs, |a, b| a + b),
-            Rule::subtract => map_op_bin(lhs, rhs, |a, b| a - b),
-            Rule::multiply => map_op_bin(lhs, rhs, |a, b| a * b),
-            Rule::divide => map_op_bin(lhs, rhs, |a, b| a / b),
-            Rule::modulo => map_op_bin(lhs, rhs, |a, b| a % b),
-            Rule::and_op => map_op_bin(lhs, rhs, |a, b| a & b),
-            Rule::or_op => map_op_bin(lhs, rhs, |a, b| a | b),
-            Rule::lshift => map_op_bin(lhs, rhs, |a, b| a << b),
-            Rule::rshift => map_op_bin(lhs, rhs, |a, b| a >> b),
-            _ => unreachable!(),
+        .map_infix(|lhs, op, rhs| {
+            let op_error = |kind| vec![SymbolError::with_kind(op.as_span(), current_file, kind)];
+            match op.as_rule() {
+                Rule::add_op => map_op_bin(lhs, rhs, |a, b| a.wrapping_add(b)),
+                Rule::subtract => map_op_bin(lhs, rhs, |a, b| a.wrapping_sub(b)),
+                Rule::multiply => map_op_bin(lhs, rhs, |a, b| a.wrapping_mul(b)),
+                Rule::divide | Rule::modulo => {
+                    map_op_bin(lhs, rhs, |a, b| (a, b)).and_then(|(a, b)| match b {
+                        0 => Err(op_error(SymbolErrorKind::DivisionByZero)),
+                        _ if op.as_rule() == Rule::divide => Ok(a.wrapping_div(b)),
+                        _ => Ok(a.wrapping_rem(b)),
+                    })
+                }
+                Rule::and_op => map_op_bin(lhs, rhs, |a, b| a & b),
+                Rule::or_op => map_op_bin(lhs, rhs, |a, b| a | b),
+                Rule::lshift | Rule::rshift => {
+                    map_op_bin(lhs, rhs, |a, b| (a, b)).and_then(|(a, b)| match b {
+                        0..=31 if op.as_rule() == Rule::lshift => Ok(a << b),
+                        0..=31 => Ok(a >> b),
+                        _ => Err(op_error(SymbolErrorKind::ShiftOverflow(b))),
+                    })
+                }
+                Rule::equal => map_op_bin(lhs, rhs, |a, b| truth(a == b)),
+                Rule::not_equal => map_op_bin(lhs, rhs, |a, b| truth(a != b)),
+                Rule::less => map_op_bin(lhs, rhs, |a, b| truth(a < b)),
+                Rule::less_equal => map_op_bin(lhs, rhs, |a, b| truth(a <= b)),
+                Rule::greater => map_op_bin(lhs, rhs, |a, b| truth(a > b)),
+                Rule::greater_equal => map_op_bin(lhs, rhs, |a, b| truth(a >= b)),
+                Rule::logical_and => map_op_bin(lhs, rhs, |a, b| truth(a != 0 && b != 0)),
+                Rule::logical_or => map_op_bin(lhs, rhs, |a, b| truth(a != 0 || b != 0)),
+                _ => unreachable!(),
+            }
         })
         .parse(pairs)
 }
@@ -93,6 +332,7 @@ pub fn parse_expression<'b, M: SymbolMap>(
 mod test {
     use super::*;
     use pest::Parser;
+    use std::{collections::HashMap, path::Path};
 
     #[test]
     fn test_example() {
@@ -101,6 +341,26 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_expression() {
+        let symbols = HashMap::from([("*".to_owned(), 0x1000), ("X".to_owned(), 3)]);
+        let eval = |s| {
+            let pairs = ASMParser::parse(Rule::expression, s).unwrap();
+            parse_expression(pairs, &symbols, FileRef::new(Path::new("test.s"), s)).unwrap()
+        };
+        assert_eq!(eval("2+3*4"), 14);
+        assert_eq!(eval("1<<2+1"), 5);
+        assert_eq!(eval("X+1=4 && 2<>2 || X>=3"), -1);
+        assert_eq!(eval("'AB'"), 0x4142);
+        assert_eq!(eval("*+2"), 0x1002);
+        assert_eq!(eval("HI($12345678)+LO(-1)"), 0x1234 + 0xFFFF);
+        assert_eq!(eval("STRLEN(\"a\\\"b\")"), 3);
+        assert_eq!(eval("0x1F+1Fh+0FFH+$-1"), 0x1F + 0x1F + 0xFF - 1);
+        assert_eq!(eval("0b1010+%1010+1010b+0o17+17q+@17"), 30 + 45);
+        assert_eq!(eval("%1111_0000+1_000+$FF_FF"), 0xF0 + 1000 + 0xFFFF);
+        assert_eq!(eval("0bh"), 0xB);
+    }
+
     // #[test]
     // fn test_matrix_multiply() {
     // 	if let Err(e) = ASMParser::parse(Rule::program, include_str!("../example_asm/MatrixMultiply.s")) {
@@ -0,0 +1,166 @@
+use pest::error::{Error, ErrorVariant, InputLocation};
+
+use crate::parser::{is_mnemonic, Rule};
+
+/// Instructions, to suggest one for a misspelled mnemonic. `test_lists_match_grammar` checks that
+/// these and the directives are the mnemonics of the grammar.
+const INSTRUCTIONS: &[&str] = &[
+    "ADD", "ADDA", "ADDI", "ANDI", "BCC", "BCS", "BEQ", "BGE", "BGT", "BHI", "BKPT", "BLE", "BLS",
+    "BLT", "BMI", "BNE", "BPL", "BRA", "BREAK", "BSR", "BVC", "BVS", "CLR", "CMP", "CMPA", "CMPI",
+    "JMP", "JSR", "LEA", "LINK", "MOVE", "MOVEA", "MOVEM", "NOP", "PEA", "RTE", "RTS", "SUB",
+    "SUBA", "SUBI", "TRAP", "UNLK",
+];
+
+/// Directives, which are written where the instructions are
+const DIRECTIVES: &[&str] = &[
+    "ALIGN", "ASSERT", "BSS", "CNOP", "CODE", "DATA", "DC", "DS", "END", "EQU", "ERROR", "EVEN",
+    "FAIL", "GLOBAL", "INCBIN", "INCLUDE", "OFFSET", "ONCE", "OPT", "ORG", "PRINT", "REG", "RS",
+    "RSRESET", "RSSET", "SECTION", "SET", "WARNING", "XDEF",
+];
+
+/// Number of characters that have to be inserted, removed, replaced or swapped to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    // Distances between the prefixes of `a` and the prefixes of `b`, for the last three rows
+    let mut before = Vec::new();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+/// The candidate closest to `word`, if it is close enough to be a typo of it. Candidates equal to
+/// `word` are not suggested.
+pub fn closest<'c, I: IntoIterator<Item = &'c str>>(word: &str, candidates: I) -> Option<&'c str> {
+    let max_distance = (word.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != word)
+        .map(|candidate| (edit_distance(word, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        // The first of the closest ones, so the suggestion does not depend on the order of a map
+        .min_by(|(a_distance, a), (b_distance, b)| a_distance.cmp(b_distance).then(a.cmp(b)))
+        .map(|(_, candidate)| candidate)
+}
+
+/// Known mnemonic that `word` is a typo of, keeping its size suffix
+pub fn mnemonic(word: &str) -> Option<String> {
+    let (base, size) = word.find('.').map_or((word, ""), |dot| word.split_at(dot));
+    let upper = base.to_ascii_uppercase();
+    let mnemonics = INSTRUCTIONS.iter().chain(DIRECTIVES).copied();
+    if base.is_empty() || mnemonics.clone().any(|mnemonic| mnemonic == upper) {
+        return None;
+    }
+    let suggestion = closest(&upper, mnemonics)?;
+    // Written in the same case as the source
+    let suggestion = if base.chars().all(|c| !c.is_ascii_uppercase()) {
+        suggestion.to_ascii_lowercase()
+    } else {
+        suggestion.to_owned()
+    };
+    Some(format!("{suggestion}{size}"))
+}
+
+/// Mnemonic that the statement of a syntax error may have meant, when one was expected
+pub fn for_syntax_error(err: &Error<Rule>, source: &str) -> Option<String> {
+    let ErrorVariant::ParsingError { positives, .. } = &err.variant else {
+        return None;
+    };
+    if !positives.iter().any(is_mnemonic) {
+        return None;
+    }
+    let pos = match err.location {
+        InputLocation::Pos(pos) | InputLocation::Span((pos, _)) => pos,
+    };
+    let line_start = source[..pos].rfind('\n').map_or(0, |newline| newline + 1);
+    let line = source[line_start..].lines().next().unwrap_or_default();
+    let line = line.split(';').next().unwrap_or_default();
+    let mut words = line.split_whitespace();
+    // A misspelled mnemonic is taken as a label, unless there is one before it at the start of the line
+    if !line.starts_with([' ', '\t']) {
+        words.next();
+    }
+    mnemonic(words.next()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("MOVEE", "MOVE"), 1);
+        assert_eq!(edit_distance("MVOE", "MOVE"), 1);
+        assert_eq!(edit_distance("loop", "lop2"), 2);
+        assert_eq!(edit_distance("", "RTS"), 3);
+    }
+
+    /// Case-insensitive words of the grammar `rules`, without the comments and the size suffixes
+    fn grammar_words<'g>(rules: impl Iterator<Item = &'g str>) -> Vec<String> {
+        let mut words = Vec::new();
+        for line in rules {
+            let line = line.split("//").next().unwrap_or_default();
+            for literal in line.split("^\"").skip(1) {
+                let literal = literal.split('"').next().unwrap_or_default();
+                let word = literal.split('.').next().unwrap_or_default();
+                if word.len() > 1 && word.chars().all(|c| c.is_ascii_alphabetic()) {
+                    words.push(word.to_owned());
+                }
+            }
+        }
+        words.sort_unstable();
+        words.dedup();
+        words
+    }
+
+    /// Every mnemonic of the grammar can be suggested, and nothing else
+    #[test]
+    fn test_lists_match_grammar() {
+        let grammar = include_str!("asm2.pest");
+        let rule = |name: &'static str| {
+            grammar
+                .lines()
+                .filter(move |line| line.split('=').next().map(str::trim) == Some(name))
+        };
+        let conditions = grammar_words(rule("cc").chain(rule("bcc_cc")));
+        // Operands and functions of the expressions
+        let operands = ["DEF", "HI", "LO", "SIZEOF", "SR", "STRLEN", "USP"];
+        let mut expected = grammar_words(grammar.lines())
+            .into_iter()
+            .filter(|word| !conditions.contains(word) && !operands.contains(&word.as_str()))
+            .chain(
+                grammar_words(rule("bcc_cc"))
+                    .iter()
+                    .map(|cc| format!("B{cc}")),
+            )
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+        let mut listed = INSTRUCTIONS
+            .iter()
+            .chain(DIRECTIVES)
+            .map(|mnemonic| mnemonic.to_string())
+            .collect::<Vec<_>>();
+        listed.sort_unstable();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn test_mnemonic() {
+        assert_eq!(mnemonic("MOVEE.L").as_deref(), Some("MOVE.L"));
+        assert_eq!(mnemonic("jrs").as_deref(), Some("jsr"));
+        assert_eq!(mnemonic("MOVE.L"), None);
+        assert_eq!(mnemonic("XYZZY"), None);
+    }
+}
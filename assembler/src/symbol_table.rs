@@ -0,0 +1,438 @@
+use std::{
+    collections::{hash_map::Entry as MapEntry, HashMap},
+    fmt::Display,
+    path::Path,
+};
+
+use pest::Span;
+
+use crate::{
+    codegen::{
+        section::{SectionKind, Sections},
+        symbols::{is_internal, References, Symbol, SymbolKind},
+    },
+    file::FileRef,
+    utils::JsonStr,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Entry<'a> {
+    name: &'a str,
+    symbol: Symbol,
+    /// Name and kind of the section, `None` if absolute
+    section: Option<(&'a str, SectionKind)>,
+    /// Exported with `XDEF`
+    exported: bool,
+    /// File and line, `None` if it was not defined on the source
+    defined_at: Option<(&'a Path, usize)>,
+}
+
+impl Entry<'_> {
+    /// Whether it is the address of a label in a section, and not a constant
+    const fn is_address(&self) -> bool {
+        matches!(self.symbol.kind, SymbolKind::Label) && self.section.is_some()
+    }
+}
+
+/// Final value of every symbol, sorted by name
+#[derive(Debug, Clone)]
+pub struct SymbolTable<'a> {
+    entries: Vec<Entry<'a>>,
+}
+
+const fn kind_name(kind: SymbolKind) -> &'static str {
+    match kind {
+        SymbolKind::Label => "label",
+        SymbolKind::Equ => "equ",
+        SymbolKind::RegisterList => "reg",
+        SymbolKind::Set => "set",
+        SymbolKind::Define => "define",
+    }
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn new(
+        symbols: &HashMap<&'a str, Symbol>,
+        definitions: &HashMap<&'a str, (Span<'a>, FileRef<'a>)>,
+        exported: &HashMap<&'a str, (Span<'a>, FileRef<'a>)>,
+        sections: &Sections<'a>,
+    ) -> Self {
+        let section_names = sections
+            .iter()
+            .map(|section| (section.name, section.kind))
+            .collect::<Vec<_>>();
+        let mut entries = symbols
+            .iter()
+            .filter(|(&name, _)| !is_internal(name))
+            .map(|(&name, &symbol)| Entry {
+                name,
+                symbol,
+                section: symbol.section.map(|idx| section_names[idx]),
+                defined_at: definitions
+                    .get(name)
+                    .map(|(span, file)| (file.path, span.start_pos().line_col().0)),
+                exported: exported.contains_key(name),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|entry| entry.name);
+        Self { entries }
+    }
+
+    /// Keeps only the symbols exported with `XDEF`
+    pub fn only_exported(mut self) -> Self {
+        self.entries.retain(|entry| entry.exported);
+        self
+    }
+
+    fn by_value(&self) -> Vec<&Entry<'a>> {
+        let mut by_value = self.entries.iter().collect::<Vec<_>>();
+        by_value.sort_by_key(|entry| entry.symbol.value);
+        by_value
+    }
+
+    pub const fn text(&self) -> TextSymbolTable<'_> {
+        TextSymbolTable(self)
+    }
+
+    pub const fn json(&self) -> JsonSymbolTable<'_> {
+        JsonSymbolTable(self)
+    }
+
+    /// C header, `guard` is the name of the include guard macro
+    pub const fn c_header<'b>(&'b self, guard: &'b str) -> CHeader<'b, 'a> {
+        CHeader { table: self, guard }
+    }
+
+    pub const fn ld_script(&self) -> LdScript<'_> {
+        LdScript(self)
+    }
+
+    /// Symbols written to a C header or a linker script, register lists are neither constants nor addresses
+    fn exported_entries(&self) -> impl Iterator<Item = &Entry<'a>> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.symbol.kind != SymbolKind::RegisterList)
+    }
+
+    /// Symbols that can not be written to a C header, with why
+    pub fn c_header_errors(&self) -> Vec<(&'a str, String)> {
+        // The first symbol that gives each identifier, in order of name
+        let mut identifiers = HashMap::<String, &str>::new();
+        let mut errors = Vec::new();
+        for entry in self.exported_entries() {
+            let name = c_identifier(entry.name);
+            if C_KEYWORDS.contains(&name.as_str()) {
+                errors.push((entry.name, format!("`{name}` is a keyword of C")));
+                continue;
+            }
+            let mut written = vec![name.clone()];
+            if entry.is_address() {
+                written.push(format!("{name}_ADDR"));
+            }
+            for identifier in written {
+                match identifiers.entry(identifier) {
+                    MapEntry::Occupied(first) => errors.push((
+                        entry.name,
+                        format!("It is written as `{}`, like `{}`", first.key(), first.get()),
+                    )),
+                    MapEntry::Vacant(first) => {
+                        first.insert(entry.name);
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    /// Symbols that can not be written to a linker script, with why
+    pub fn ld_script_errors(&self) -> Vec<(&'a str, String)> {
+        self.exported_entries()
+            .filter_map(|entry| {
+                let mut chars = entry.name.chars();
+                let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || matches!(c, '_' | '.'))
+                    && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.'));
+                if !valid {
+                    Some((
+                        entry.name,
+                        "Names of linker scripts only have letters, digits, `_` and `.`".into(),
+                    ))
+                } else if LD_KEYWORDS.contains(&entry.name) {
+                    Some((entry.name, format!("`{}` is a keyword of linker scripts", entry.name)))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub const fn cross_reference<'b>(
+        &'b self,
+        references: &'b References<'a>,
+    ) -> CrossReference<'b, 'a> {
+        CrossReference {
+            table: self,
+            references,
+        }
+    }
+}
+
+pub struct TextSymbolTable<'a>(&'a SymbolTable<'a>);
+
+impl Display for Entry<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<32} {:08X}  {:<7} {:<12}",
+            self.name,
+            self.symbol.value,
+            kind_name(self.symbol.kind),
+            self.section.map_or("-", |(name, _)| name)
+        )?;
+        match self.defined_at {
+            Some((path, line)) => write!(f, " {}:{line}", path.display()),
+            None if self.symbol.kind == SymbolKind::Define => write!(f, " <command line>"),
+            None => write!(f, " -"),
+        }
+    }
+}
+
+impl Display for TextSymbolTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let header = format!(
+            "{:<32} {:<8}  {:<7} {:<12} Defined at",
+            "Name", "Value", "Kind", "Section"
+        );
+        writeln!(f, "Symbols sorted by name")?;
+        writeln!(f)?;
+        writeln!(f, "{header}")?;
+        for entry in &self.0.entries {
+            writeln!(f, "{entry}")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Symbols sorted by value")?;
+        writeln!(f)?;
+        writeln!(f, "{header}")?;
+        for entry in self.0.by_value() {
+            writeln!(f, "{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct JsonSymbolTable<'a>(&'a SymbolTable<'a>);
+
+impl Display for JsonSymbolTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{{")?;
+        writeln!(f, "  \"symbols\": [")?;
+        for (i, entry) in self.0.entries.iter().enumerate() {
+            write!(
+                f,
+                "    {{\"name\": {}, \"value\": {}, \"kind\": \"{}\", \"section\": ",
+                JsonStr(entry.name),
+                entry.symbol.value,
+                kind_name(entry.symbol.kind)
+            )?;
+            match entry.section {
+                Some((section, _)) => write!(f, "{}", JsonStr(section))?,
+                None => write!(f, "null")?,
+            }
+            write!(f, ", \"exported\": {}", entry.exported)?;
+            match entry.defined_at {
+                Some((path, line)) => write!(
+                    f,
+                    ", \"file\": {}, \"line\": {line}}}",
+                    JsonStr(&path.to_string_lossy())
+                )?,
+                None => write!(f, ", \"file\": null, \"line\": null}}")?,
+            }
+            let separator = if i + 1 < self.0.entries.len() {
+                ","
+            } else {
+                ""
+            };
+            writeln!(f, "{separator}")?;
+        }
+        writeln!(f, "  ],")?;
+        write!(f, "  \"by_value\": [")?;
+        for (i, entry) in self.0.by_value().into_iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{separator}{}", JsonStr(entry.name))?;
+        }
+        writeln!(f, "]")?;
+        writeln!(f, "}}")
+    }
+}
+
+/// Symbols with where they are defined and used, appended to the listing
+pub struct CrossReference<'b, 'a> {
+    table: &'b SymbolTable<'a>,
+    references: &'b References<'a>,
+}
+
+impl Display for CrossReference<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f)?;
+        writeln!(f, "{:=^130}", " Cross-reference ")?;
+        writeln!(f)?;
+        for entry in &self.table.entries {
+            write!(f, "{:<32} {:08X}  ", entry.name, entry.symbol.value)?;
+            match entry.defined_at {
+                Some((path, line)) => writeln!(f, "defined at {}:{line}", path.display())?,
+                None if entry.symbol.kind == SymbolKind::Define => {
+                    writeln!(f, "defined on the command line")?
+                }
+                None => writeln!(f, "defined by the assembler")?,
+            }
+            match self.references.get(entry.name) {
+                Some(uses) => {
+                    for (path, line) in uses {
+                        writeln!(f, "{:43}used at {}:{line}", "", path.display())?;
+                    }
+                }
+                None => writeln!(f, "{:43}*** unreferenced ***", "")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keywords of C, which can not be the names of its constants and declarations
+const C_KEYWORDS: [&str; 44] = [
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "_Alignas",
+    "_Alignof",
+    "_Atomic",
+    "_Bool",
+    "_Complex",
+    "_Generic",
+    "_Imaginary",
+    "_Noreturn",
+    "_Static_assert",
+    "_Thread_local",
+];
+
+/// Commands and functions of GNU ld linker scripts, which are taken as such instead of as symbols
+const LD_KEYWORDS: [&str; 30] = [
+    "ABSOLUTE",
+    "ADDR",
+    "ALIGN",
+    "ASSERT",
+    "BYTE",
+    "CONSTANT",
+    "CREATE_OBJECT_SYMBOLS",
+    "DEFINED",
+    "ENTRY",
+    "EXTERN",
+    "FILL",
+    "GROUP",
+    "INCLUDE",
+    "INPUT",
+    "KEEP",
+    "LENGTH",
+    "LOADADDR",
+    "LONG",
+    "MAX",
+    "MEMORY",
+    "MIN",
+    "NEXT",
+    "ORIGIN",
+    "OUTPUT",
+    "PROVIDE",
+    "QUAD",
+    "SECTIONS",
+    "SHORT",
+    "SIZEOF",
+    "SQUAD",
+];
+
+/// Symbol name usable as a C identifier
+fn c_identifier(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Include guard macro for a header written to `path`
+pub fn include_guard(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    c_identifier(&name).to_uppercase()
+}
+
+/// `#define` for constants, and `extern` declarations and `_ADDR` macros for labels
+pub struct CHeader<'b, 'a> {
+    table: &'b SymbolTable<'a>,
+    guard: &'b str,
+}
+
+impl Display for CHeader<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "/* Generated by p68kasm, do not edit */")?;
+        writeln!(f, "#ifndef {}", self.guard)?;
+        writeln!(f, "#define {}", self.guard)?;
+        writeln!(f)?;
+        for entry in self.table.exported_entries() {
+            let name = c_identifier(entry.name);
+            let value = entry.symbol.value;
+            match entry.section {
+                Some((_, kind)) if entry.is_address() => {
+                    if kind == SectionKind::Code {
+                        writeln!(f, "extern void {name}(void);")?;
+                    } else {
+                        writeln!(f, "extern char {name}[];")?;
+                    }
+                    writeln!(f, "#define {name}_ADDR 0x{value:08X}UL")?;
+                }
+                _ => writeln!(f, "#define {name} 0x{value:08X}UL")?,
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "#endif")
+    }
+}
+
+/// Symbol assignments for a GNU ld linker script
+pub struct LdScript<'a>(&'a SymbolTable<'a>);
+
+impl Display for LdScript<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "/* Generated by p68kasm, do not edit */")?;
+        for entry in self.0.exported_entries() {
+            writeln!(f, "{} = 0x{:08X};", entry.name, entry.symbol.value)?;
+        }
+        Ok(())
+    }
+}
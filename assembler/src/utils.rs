@@ -47,3 +47,23 @@ impl<S: Display, T: Display, I: Iterator<Item = T> + Clone> Display for PrintIte
         Ok(())
     }
 }
+
+/// JSON string literal
+pub struct JsonStr<'a>(pub &'a str);
+
+impl Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\t' => write!(f, "\\t")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}